@@ -0,0 +1,88 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::net::IpAddr;
+use ipnetwork::IpNetwork;
+
+/**
+ * Helpers for turning packet addresses into stable key components
+ */
+
+// Canonical form of an address, IPv4-mapped IPv6 addresses (::ffff:a.b.c.d) are turned back into
+// IPv4 so the same client always lands in the same bucket, and is matched by IPv4 whitelists.
+pub fn normalize(ip: &IpAddr) -> IpAddr {
+    ip.to_canonical()
+}
+
+// Render a source address for use in a key. IPv6 addresses can optionally be aggregated to their
+// network prefix, e.g. a /64, as a single client can rotate through every address in it.
+pub fn source_key(ip: &IpAddr, ipv6_prefix: Option<u8>) -> String {
+    match (normalize(ip), ipv6_prefix) {
+        (IpAddr::V6(addr), Some(prefix)) => match IpNetwork::new(IpAddr::V6(addr), prefix) {
+            Ok(network) => format!("{}/{}", network.network(), prefix),
+            Err(_) => addr.to_string(),
+        },
+        (addr, _) => addr.to_string(),
+    }
+}
+
+// The address or network at the end of a key, e.g: {ja3}-{src}, None-{src} or {ja3}-{src_net/24}.
+// None for keys which don't end in one, such as a bare {ja3}
+pub fn key_address(key: &str) -> Option<IpNetwork> {
+    key.rsplit('-').next()?.parse().ok()
+}
+
+// Render a network as a plain address for a single host, and in CIDR notation otherwise
+pub fn network_string(network: &IpNetwork) -> String {
+    let host = match network {
+        IpNetwork::V4(_) => 32,
+        IpNetwork::V6(_) => 128,
+    };
+    if network.prefix() == host {
+        network.ip().to_string()
+    } else {
+        format!("{}/{}", network.network(), network.prefix())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let mapped: IpAddr = "::ffff:192.168.0.7".parse().unwrap();
+        assert_eq!(normalize(&mapped), "192.168.0.7".parse::<IpAddr>().unwrap());
+
+        let v6: IpAddr = "2001:0DB8:0000:0000:0000:0000:0000:0001".parse().unwrap();
+        assert_eq!(normalize(&v6).to_string(), "2001:db8::1");
+    }
+
+    #[test]
+    fn test_source_key() {
+        let v4: IpAddr = "192.168.0.7".parse().unwrap();
+        assert_eq!(source_key(&v4, None), "192.168.0.7");
+        assert_eq!(source_key(&v4, Some(64)), "192.168.0.7");
+
+        let v6: IpAddr = "2001:db8:1:2:aaaa:bbbb:cccc:dddd".parse().unwrap();
+        assert_eq!(source_key(&v6, None), "2001:db8:1:2:aaaa:bbbb:cccc:dddd");
+        assert_eq!(source_key(&v6, Some(64)), "2001:db8:1:2::/64");
+        assert_eq!(source_key(&v6, Some(48)), "2001:db8:1::/48");
+
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert_eq!(source_key(&mapped, Some(64)), "10.0.0.1");
+    }
+
+    #[test]
+    fn test_key_address() {
+        assert_eq!(key_address("579ccef312d18482fc42e2b822ca2430-192.168.0.7"), Some("192.168.0.7".parse().unwrap()));
+        assert_eq!(key_address("None-2001:db8:1:2::/64"), Some("2001:db8:1:2::/64".parse().unwrap()));
+        assert_eq!(key_address("192.168.0.0/24"), Some("192.168.0.0/24".parse().unwrap()));
+        assert_eq!(key_address("579ccef312d18482fc42e2b822ca2430"), None);
+        assert_eq!(key_address("579ccef312d18482fc42e2b822ca2430-192.168.0.7:443"), None);
+
+        assert_eq!(network_string(&"192.168.0.7".parse().unwrap()), "192.168.0.7");
+        assert_eq!(network_string(&"192.168.0.7/24".parse().unwrap()), "192.168.0.0/24");
+    }
+}
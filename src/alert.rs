@@ -0,0 +1,106 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+// What an alert asks of the sinks
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AlertKind {
+    #[default]
+    Block, // the key violated its threshold, block it for block_seconds
+    Release, // a blocked key stayed under its threshold for the --release-cooldown, the block can be lifted early
+    Novel, // a ja3 never seen before, informational, nothing is blocked
+}
+
+impl AlertKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::Block => "block",
+            AlertKind::Release => "release",
+            AlertKind::Novel => "novel",
+        }
+    }
+}
+
+// What a keyspace counted for a key, each with its own buckets and threshold
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Metric {
+    #[default]
+    Handshake, // TLS ClientHellos
+    Control, // TCP SYN, FIN and RST packets without a ja3
+}
+
+impl Metric {
+    // the metric a packet counts towards, by whether it had a ja3
+    pub fn of(ja3: Option<&str>) -> Metric {
+        match ja3 {
+            Some(_) => Metric::Handshake,
+            None => Metric::Control,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Metric::Handshake => "handshake",
+            Metric::Control => "control",
+        }
+    }
+}
+
+// A threshold violation, as passed from the monitor to the alerter
+#[derive(Debug, Clone)]
+pub(crate) struct Alert {
+    pub kind: AlertKind,
+    pub keyspace: String, // name of the keyspace which fired
+    pub metric: Metric, // what tripped the threshold
+    pub key: String, // the violating key, e.g. {ja3}-{src}
+    pub ja3: Option<String>, // the md5 ja3 hash of the packet which tripped the threshold
+    pub source: IpAddr, // source address of the packet which tripped the threshold
+    pub count: u64, // the count in the window when it tripped, or when it was released
+    pub threshold: u64, // the threshold it exceeded
+    pub window: Duration, // the keyspace window, re-alerts are suppressed within it
+    pub block_seconds: u32, // how long the key should be blocked for
+    pub ts: SystemTime, // capture time of the packet which tripped the threshold, or of the release
+    pub evidence: Evidence, // what the bucket of the key saw
+}
+
+#[cfg(test)]
+impl Alert {
+    // a block of the key at ts, 1001 handshakes of the TEST_JA3 from 8.8.8.8 over a threshold of 1000 in
+    // the default keyspace, for the tests to override the fields they care about
+    pub fn test(key: &str, ts: SystemTime) -> Alert {
+        Alert {
+            kind: AlertKind::Block,
+            keyspace: "default".to_string(),
+            metric: Metric::Handshake,
+            key: key.to_string(),
+            ja3: Some(crate::monitor::TEST_JA3.to_string()),
+            source: "8.8.8.8".parse().unwrap(),
+            count: 1001,
+            threshold: 1000,
+            window: Duration::from_secs(60),
+            block_seconds: 86400,
+            ts,
+            evidence: Evidence::default(),
+        }
+    }
+}
+
+// Why a key alerted, from its bucket
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Evidence {
+    pub sources: Vec<IpAddr>, // distinct source addresses seen, up to MAX_EVIDENCE
+    pub destinations: Vec<SocketAddr>, // distinct destination addresses and ports seen, up to MAX_EVIDENCE
+    pub first_seen: Option<SystemTime>, // capture time of the first packet of the bucket
+    pub last_seen: Option<SystemTime>, // capture time of the latest packet of the bucket
+    pub slot: Duration, // the length of a histogram slot
+    pub histogram: Vec<(SystemTime, u64)>, // the counts of the rolling window, by slot start time
+    pub distinct_sources: Option<u64>, // estimated distinct sources of the ja3 in the window, for cardinality alerts
+    pub distinct_networks: Option<u64>, // estimated distinct /24 or /48 networks of the ja3 in the window
+}
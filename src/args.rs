@@ -2,28 +2,59 @@
 // Licensed under the MIT License (https://opensource.org/licenses/MIT)
 // This file may not be copied, modified, or distributed except according to those terms.
 
-use clap::Parser;
-use ipnetwork::Ipv4Network;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use ipnetwork::IpNetwork;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::baseline::{BaselineSettings, BASELINE_KEYSPACE};
+use crate::cardinality::{CardinalitySettings, CARDINALITY_KEYSPACE};
+use crate::connections::{ConnectionSettings, CONNECTIONS_KEYSPACE};
+use crate::novelty::{NoveltySettings, NOVEL_KEYSPACE};
+use crate::config::FileConfig;
+use crate::httpauth::{HttpAuthConfig, Secret};
+use crate::keyspace::{Keyspace, KeyspaceSpec, DEFAULT_KEYSPACE};
+use crate::keytemplate::{KeyTemplate, JA3_SRC_TEMPLATE, JA3_TEMPLATE};
+use crate::overrides::Override;
+use crate::retry::RetryPolicy;
+use crate::sink::{SinkConfig, SinkKind};
+use crate::rollingwindow::{self, MAX_SLOTS};
+use crate::whitelist::Whitelist;
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
-#[group(id = "input", required = true)]
+#[group(id = "input")]
 pub struct AppArgs {
     #[arg(short, long, group = "input", help = "Network device to sniff, e.g: eth0")]
     pub interface: Option<String>,
 
     // log file pattern
-    #[arg(short, long, group = "input", help = "The pcap file to analyse for testing. NOTE: timing windows dont work with files")]
+    #[arg(short, long, group = "input", help = "The pcap file to analyse, windows are driven by the packet capture timestamps")]
     pub file: Option<String>,
 
     /// Threshold for triggering an alert
     #[arg(short, long, default_value_t = 1000, help = "Threshold number of occurrences of a ja3-remote_addr-uri in the window")]
-    pub threshold: u16,
+    pub threshold: u64,
+
+    /// Threshold for the TCP control packets
+    #[arg(long, help = "Threshold number of TCP control packets, SYN, FIN and RST without a ja3, of a key in the window, counted apart from the handshakes. defaults to --threshold")]
+    pub control_threshold: Option<u64>,
 
     /// Time window for the threshold (in seconds)
     #[arg(short, long, default_value_t = 60, help = "Time window in seconds for calculating the threshold")]
     pub window: u64,
 
+    /// Time window for the threshold (in milliseconds)
+    #[arg(long, help = "Time window in milliseconds, overrides --window to catch sub-second bursts, e.g: 200")]
+    pub window_ms: Option<u64>,
+
+    /// Resolution of the rolling windows (in milliseconds)
+    #[arg(long, default_value_t = 1000, help = "Slot size of the rolling window in milliseconds, counts within a slot are merged. e.g: 100 with --window-ms 500")]
+    pub slot_ms: u64,
+
     // /// Number of threads for processing
     // #[arg(short, long, default_value_t = 2, help = "The number of threads to use for parallel processing")]
     // pub concurrency: usize,
@@ -35,20 +66,156 @@ pub struct AppArgs {
     #[arg(short, long, default_value_t=false, help = "DryRun disables posting the alerts to the --alert-url")]
     pub dry_run: bool,
 
+    /// Retries of a failed alert post
+    #[arg(long, default_value_t = 3, help = "Retries of a failed alert post, non-2xx responses count as failures. alerts which still fail are queued in the outbox")]
+    pub alert_retries: u32,
+
+    /// Backoff before the first retry (in milliseconds)
+    #[arg(long, default_value_t = 200, help = "Backoff in milliseconds before the first retry, doubled every attempt with jitter")]
+    pub alert_backoff_ms: u64,
+
+    /// Cap on the retry backoff (in milliseconds)
+    #[arg(long, default_value_t = 10000, help = "Cap on the retry backoff in milliseconds, also the interval the outbox is replayed at")]
+    pub alert_backoff_max_ms: u64,
+
+    /// Outbox file for undelivered alerts
+    #[arg(long, help = "File to keep undelivered alerts in across restarts, as JSON lines. without it they are only queued in memory")]
+    pub alert_outbox: Option<String>,
+
+    /// Alerts per post when batching
+    #[arg(long, default_value_t = 1, help = "Post up to this many alerts at once as a JSON array, to keep up with thousands of keys alerting at the same time. 1 posts every alert on its own")]
+    pub alert_batch_size: usize,
+
+    /// Longest wait for a batch to fill (in milliseconds)
+    #[arg(long, default_value_t = 1000, help = "Milliseconds a batch waits to fill before it is posted anyway, with --alert-batch-size")]
+    pub alert_batch_delay_ms: u64,
+
+    /// Bearer token for the --alert-url
+    #[arg(long, value_parser = Secret::parse, help = "Bearer token sent to the --alert-url, read from env:NAME or file:PATH so it stays off the command line, e.g: env:SUSSPEKT_TOKEN")]
+    pub alert_bearer_token: Option<Secret>,
+
+    /// Basic auth credentials for the --alert-url
+    #[arg(long, value_parser = Secret::parse, help = "Basic auth user:password for the --alert-url, read from env:NAME or file:PATH")]
+    pub alert_basic_auth: Option<Secret>,
+
+    /// Secret for signing the alert posts
+    #[arg(long, value_parser = Secret::parse, help = "HMAC-SHA256 secret, read from env:NAME or file:PATH, to sign every post with an X-Susspekt-Signature: t=<unix time>,v1=<hex hmac of \"<t>.<body>\"> header")]
+    pub alert_hmac_secret: Option<Secret>,
+
+    /// Client certificate for mutual TLS
+    #[arg(long, help = "PEM client certificate presented to the --alert-url for mutual TLS")]
+    pub alert_client_cert: Option<String>,
+
+    /// Client key for mutual TLS
+    #[arg(long, help = "PKCS#8 PEM key of the --alert-client-cert")]
+    pub alert_client_key: Option<String>,
+
+    /// CA bundle for the --alert-url
+    #[arg(long, help = "PEM bundle of CA certificates to trust for the --alert-url, on top of the system roots")]
+    pub alert_ca_cert: Option<String>,
+
     /// Duration for blocking suspicious traffic (in seconds)
     #[arg(short, long, default_value_t = 86400, help = "Alert duration field value in seconds for how long to block suspicious traffic")]
     pub block_seconds: u32,
 
+    /// Cooldown before a blocked key is released (in seconds)
+    #[arg(long, help = "Send a release event for an alerted key once it has stayed under its threshold for this many seconds, so the block can be lifted early. off by default")]
+    pub release_cooldown: Option<u64>,
+
+    /// Escalating block times for repeat offenders (in seconds)
+    #[arg(long, value_delimiter = ',', help = "Block times in seconds for the 1st, 2nd, ... offence of a key, the last one repeats. replaces the block time posted to the --alert-url, e.g: 600,3600,86400,604800")]
+    pub block_ladder: Vec<u32>,
+
+    /// Memory of the offences (in seconds)
+    #[arg(long, default_value_t = 604800, help = "Seconds an offence is remembered for the --block-ladder, a key without an offence for longer starts again at the first step")]
+    pub offence_memory: u64,
+
+    /// Deviation from the learned baseline to alert at (in standard deviations)
+    #[arg(long, help = "Learn the normal handshake rate of every ja3 and alert when it is this many standard deviations above it, in the baseline keyspace, e.g: 4. off by default")]
+    pub baseline_sigma: Option<f64>,
+
+    /// Interval the baseline rate is measured over (in seconds)
+    #[arg(long, default_value_t = 60, help = "Seconds over which the handshakes of a ja3 are counted, for learning its baseline and comparing against it")]
+    pub baseline_interval: u64,
+
+    /// Weight of the latest interval in the baseline
+    #[arg(long, default_value_t = 0.05, help = "Weight of the latest interval in the moving mean and variance of the baseline, 0.05 remembers roughly the last 20 intervals")]
+    pub baseline_alpha: f64,
+
+    /// Learning time before a baseline alerts (in seconds)
+    #[arg(long, default_value_t = 3600, help = "Seconds a ja3 learns its baseline before it can alert")]
+    pub baseline_warmup: u64,
+
+    /// Least handshakes in an interval for a baseline alert
+    #[arg(long, default_value_t = 50, help = "Least handshakes in an interval for a baseline alert, so a rare ja3 doesn't alert on a handful")]
+    pub baseline_min_count: u64,
+
+    /// Distinct sources of a ja3 to alert at
+    #[arg(long, help = "Alert when a ja3 is seen from more than this many distinct source addresses within the window, in the cardinality keyspace, e.g: 1000. off by default")]
+    pub cardinality_limit: Option<u64>,
+
+    /// Distinct source networks of a ja3 to alert at
+    #[arg(long, help = "Alert when a ja3 is seen from more than this many distinct /24 (IPv4) or /48 (IPv6) networks within the window, in the cardinality keyspace. off by default")]
+    pub cardinality_net_limit: Option<u64>,
+
+    /// Growth of the distinct sources of a ja3 to alert at
+    #[arg(long, help = "Alert when the distinct sources of a ja3 within the window grow to more than this factor of those of the previous window, e.g: 3. off by default")]
+    pub cardinality_growth: Option<f64>,
+
+    /// Least distinct sources for a growth alert
+    #[arg(long, default_value_t = 100, help = "Least distinct sources of a ja3 within the window for a --cardinality-growth alert")]
+    pub cardinality_min_sources: u64,
+
+    /// File of the known ja3s
+    #[arg(long, help = "File of the ja3s seen before, with their first seen time, loaded at startup and appended to as new ones show up. Enables novel ja3 alerts, in the novel keyspace")]
+    pub known_ja3_file: Option<String>,
+
+    /// Training period of the known ja3s (in seconds)
+    #[arg(long, default_value_t = 86400, help = "Seconds from the first ja3 recorded in the --known-ja3-file during which new ja3s are learned without alerting")]
+    pub novel_training: u64,
+
+    /// Period a new ja3 counts as novel (in seconds)
+    #[arg(long, default_value_t = 600, help = "Seconds after a novel ja3 is first seen during which its keys are held to the --novel-threshold")]
+    pub novel_period: u64,
+
+    /// Threshold for the keys of a novel ja3
+    #[arg(long, help = "Threshold for the keys of a novel ja3 during the --novel-period, when lower than that of the keyspace or override. off by default")]
+    pub novel_threshold: Option<u64>,
+
+    /// SYNs without a ClientHello from a source to alert at
+    #[arg(long, help = "Alert when a source sends more than this many SYNs within the window which never get to a ClientHello, in the connections keyspace, e.g: 100. off by default")]
+    pub conn_half_open_limit: Option<u64>,
+
+    /// Share of the connections of a source reset to alert at
+    #[arg(long, help = "Alert when more than this share of the connections of a source within the window end with a RST, e.g: 0.5. off by default")]
+    pub conn_rst_ratio: Option<f64>,
+
+    /// Connections torn down right after the ClientHello to alert at
+    #[arg(long, help = "Alert when a source closes more than this many connections within the window within --conn-teardown-ms of the ClientHello, e.g: 100. off by default")]
+    pub conn_teardown_limit: Option<u64>,
+
+    /// Least connections of a source for a RST ratio alert
+    #[arg(long, default_value_t = 20, help = "Least connections of a source within the window for a --conn-rst-ratio alert")]
+    pub conn_min_connections: u64,
+
+    /// Time a SYN waits for its ClientHello (in milliseconds)
+    #[arg(long, default_value_t = 5000, help = "Milliseconds after the SYN by which the ClientHello must arrive, or the connection counts as half open")]
+    pub conn_hello_timeout_ms: u64,
+
+    /// Time after the ClientHello within which a close is a teardown (in milliseconds)
+    #[arg(long, default_value_t = 1000, help = "Milliseconds after the ClientHello within which a FIN or RST counts as a teardown")]
+    pub conn_teardown_ms: u64,
+
     /// Flag to parse the entire file from the beginning
     // #[arg(short, long, help = "If set, the entire log file will be parsed from the beginning before tailing")]
     // pub parse_entire_file: bool,
 
     /// Whitelist of network addresses in CIDR notation
-    #[arg(long, default_value = "10.0.0.0/8, 192.168.0.0/16", help = "Comma-separated list of whitelisted networks in CIDR notation")]
+    #[arg(long, default_value = "10.0.0.0/8, 192.168.0.0/16", help = "Comma-separated list of whitelisted IPv4 or IPv6 networks in CIDR notation")]
     pub whitelist_networks: String,
 
     /// Whitelist of JA3 hashes
-    #[arg(long, default_value = "None", help = "Optional comma-separated list of whitelisted md5_semi_ja3")]
+    #[arg(long, default_value = "", help = "Optional comma-separated list of whitelisted md5_semi_ja3")]
     pub whitelist_ja3s: String,
 
     /// Log creation of new buckets
@@ -58,29 +225,339 @@ pub struct AppArgs {
     #[arg(long, default_value_t=false, help = "add IP to the ja3 hash as a key to aggregate on, e.g: {ja3}-{remote_addr}")]
     pub agg_ip: bool,
 
+    /// Aggregate IPv6 sources by network prefix
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=128), help = "aggregate IPv6 remote_addr by network prefix length in keys, e.g: 64 for {ja3}-2001:db8:1:2::/64")]
+    pub agg_ipv6_prefix: Option<u8>,
+
+    /// Template for the aggregation key, overrides --agg-ip
+    #[arg(long, value_parser = KeyTemplate::parse, help = "template for the key to aggregate on, overrides --agg-ip, e.g: {ja3}-{src_net/24}-{dst}:{dst_port}. fields: ja3, src, dst, src_net/N[/M], dst_net/N[/M], src_port, dst_port, sni")]
+    pub key_template: Option<KeyTemplate>,
+
+    /// Named keyspaces to aggregate on at the same time
+    #[arg(long, value_parser = KeyspaceSpec::parse, help = "a named keyspace to aggregate on, can be repeated to track several at once. replaces the default keyspace from --key-template/--agg-ip. e.g: per_ip={src},threshold=5000,window=10,block_seconds=600, unset values fall back to --threshold, --control-threshold, --window and --block-seconds")]
+    pub keyspace: Vec<KeyspaceSpec>,
+
+    /// TOML config file
+    #[arg(short, long, help = "TOML config file with any of the above options, options given on the command line take precedence")]
+    pub config: Option<String>,
+
+    /// Per ja3 or key prefix overrides of the threshold, window and block time
+    #[arg(long = "override", value_parser = Override::parse, help = "override the threshold, window or block time for a ja3 or key prefix, can be repeated, the first match wins. e.g: ja3=579ccef312d18482fc42e2b822ca2430,threshold=5,block_seconds=604800 or key_prefix=None-,threshold=50000")]
+    pub overrides: Vec<Override>,
+
+    /// Alert sinks, only settable with [[sinks]] in the config file
+    #[arg(skip)]
+    pub sinks: Vec<SinkConfig>,
+
 }
 
 
 // implementation for parsing the weirder command line args
 impl AppArgs {
 
-    pub fn parse_whitelist_networks(&self) -> Vec<Ipv4Network> {
+    // parse the command line, merge in the --config file and validate the result, exiting with
+    // an error naming the bad entry if anything is off
+    pub fn load() -> AppArgs {
+        let matches = AppArgs::command().get_matches();
+        let args = AppArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        args.with_config(&matches)
+            .unwrap_or_else(|e| AppArgs::command().error(ErrorKind::InvalidValue, e).exit())
+    }
+
+    // re-read the command line and --config file, e.g. on SIGHUP. errors are returned rather than
+    // exiting, so the running config can be kept
+    pub fn reload() -> Result<AppArgs, String> {
+        let matches = AppArgs::command().try_get_matches().map_err(|e| e.to_string())?;
+        let args = AppArgs::from_arg_matches(&matches).map_err(|e| e.to_string())?;
+        args.with_config(&matches)
+    }
+
+    fn with_config(mut self, matches: &ArgMatches) -> Result<AppArgs, String> {
+        if let Some(path) = self.config.clone() {
+            let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+            FileConfig::load(&path).and_then(|config| config.apply(&mut self, from_cli))?;
+        }
+        self.validate()?;
+        Ok(self)
+    }
+
+    // checks which can only be done once the command line and config file are merged
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interface.is_none() && self.file.is_none() {
+            return Err("one of --interface or --file is required, on the command line or in the config file".to_string());
+        }
+        if self.window_duration().is_zero() {
+            return Err("window must be greater than zero".to_string());
+        }
+        if self.alert_backoff_max_ms == 0 || self.alert_backoff_max_ms < self.alert_backoff_ms {
+            return Err("alert_backoff_max_ms must be at least 1 and at least alert_backoff_ms".to_string());
+        }
+        if self.alert_batch_size == 0 || self.alert_batch_delay_ms == 0 {
+            return Err("alert_batch_size and alert_batch_delay_ms must be at least 1".to_string());
+        }
+        if self.slot_ms == 0 {
+            return Err("slot_ms must be at least 1 millisecond".to_string());
+        }
+        if self.block_ladder.contains(&0) {
+            return Err("block_ladder steps must be at least 1 second".to_string());
+        }
+        if self.offence_memory == 0 {
+            return Err("offence_memory must be at least 1 second".to_string());
+        }
+        if let Some(baseline) = self.baseline() {
+            if !(baseline.sigma > 0.0 && baseline.sigma.is_finite()) {
+                return Err("baseline_sigma must be greater than zero".to_string());
+            }
+            if !(baseline.alpha > 0.0 && baseline.alpha <= 1.0) {
+                return Err("baseline_alpha must be greater than zero and at most 1".to_string());
+            }
+            self.validate_window(baseline.interval).map_err(|e| format!("{} in baseline_interval", e))?;
+        }
+        if let Some(cardinality) = self.cardinality() {
+            if cardinality.growth.is_some_and(|growth| !(growth > 1.0 && growth.is_finite())) {
+                return Err("cardinality_growth must be greater than 1".to_string());
+            }
+        }
+        if let Some(connections) = self.connections() {
+            if connections.rst_ratio.is_some_and(|ratio| !(ratio > 0.0 && ratio < 1.0)) {
+                return Err("conn_rst_ratio must be greater than zero and less than 1".to_string());
+            }
+            if connections.hello_timeout.is_zero() {
+                return Err("conn_hello_timeout_ms must be at least 1 millisecond".to_string());
+            }
+        }
+        if self.novel_threshold == Some(0) {
+            return Err("novel_threshold must be at least 1".to_string());
+        }
+        // keyspaces are told apart by name, by the reload, the dedup and the block ladder
+        let keyspaces = self.keyspaces();
+        let reserved = self.reserved_keyspaces();
+        for (n, keyspace) in keyspaces.iter().enumerate() {
+            self.validate_window(keyspace.window).map_err(|e| format!("{} in keyspace '{}'", e, keyspace.name))?;
+            if keyspaces[..n].iter().any(|other| other.name == keyspace.name) {
+                return Err(format!("keyspace name '{}' is used more than once", keyspace.name));
+            }
+            if reserved.contains(&keyspace.name.as_str()) {
+                return Err(format!("the keyspace name '{}' is taken by the {} alerts", keyspace.name, keyspace.name));
+            }
+        }
+        for o in self.overrides.iter().filter(|o| o.window.is_some()) {
+            self.validate_window(o.window.unwrap()).map_err(|e| format!("{} in override for {:?}", e, o.matcher))?;
+        }
+        self.http_auth().validate()?;
+        for (n, sink) in self.sinks.iter().enumerate() {
+            sink.validate()?;
+            if sink.kind == SinkKind::Http {
+                sink.http_auth(self.http_auth()).validate().map_err(|e| format!("sink '{}': {}", sink.name(), e))?;
+                sink.validate_batching(self.alert_batch_size)?;
+            }
+            let shared = self.sinks[..n].iter().any(|other| {
+                (sink.outbox.is_some() && other.outbox == sink.outbox) ||
+                    (sink.state.is_some() && other.state == sink.state) ||
+                    (sink.kind == SinkKind::File && other.kind == SinkKind::File && other.path == sink.path)
+            });
+            if shared {
+                return Err(format!("sink '{}' shares its file with another sink", sink.name()));
+            }
+        }
+        self.parse_whitelist_networks()?;
+        self.parse_whitelist_ja3()?;
+        Ok(())
+    }
+
+    // a window must cover at least one slot, and at most MAX_SLOTS so a bucket stays small
+    fn validate_window(&self, window: Duration) -> Result<(), String> {
+        if window < self.slot() {
+            return Err(format!("window {:?} is shorter than the slot {:?}, lower --slot-ms", window, self.slot()));
+        }
+        let slots = rollingwindow::slots(window, self.slot());
+        if slots > MAX_SLOTS {
+            return Err(format!("window {:?} needs {} slots of {:?}, more than the {} allowed, raise --slot-ms", window, slots, self.slot(), MAX_SLOTS));
+        }
+        Ok(())
+    }
+
+    // the window, from --window-ms if given, else --window
+    pub fn window_duration(&self) -> Duration {
+        match self.window_ms {
+            Some(ms) => Duration::from_millis(ms),
+            None => Duration::from_secs(self.window),
+        }
+    }
+
+    pub fn slot(&self) -> Duration {
+        Duration::from_millis(self.slot_ms)
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.alert_retries,
+            backoff: Duration::from_millis(self.alert_backoff_ms),
+            max_backoff: Duration::from_millis(self.alert_backoff_max_ms),
+        }
+    }
+
+    pub fn http_auth(&self) -> HttpAuthConfig {
+        HttpAuthConfig {
+            bearer_token: self.alert_bearer_token.clone(),
+            basic_auth: self.alert_basic_auth.clone(),
+            hmac_secret: self.alert_hmac_secret.clone(),
+            client_cert: self.alert_client_cert.clone(),
+            client_key: self.alert_client_key.clone(),
+            ca_cert: self.alert_ca_cert.clone(),
+        }
+    }
+
+    // None unless release events are enabled
+    pub fn release_cooldown(&self) -> Option<Duration> {
+        self.release_cooldown.map(Duration::from_secs)
+    }
+
+    // None unless baseline detection is enabled
+    pub fn baseline(&self) -> Option<BaselineSettings> {
+        self.baseline_sigma.map(|sigma| BaselineSettings {
+            sigma,
+            interval: Duration::from_secs(self.baseline_interval),
+            slot: self.slot(),
+            alpha: self.baseline_alpha,
+            warmup: Duration::from_secs(self.baseline_warmup),
+            min_count: self.baseline_min_count,
+            block_seconds: self.block_seconds,
+        })
+    }
+
+    // None unless one of the cardinality limits is set
+    pub fn cardinality(&self) -> Option<CardinalitySettings> {
+        if self.cardinality_limit.is_none() && self.cardinality_net_limit.is_none() && self.cardinality_growth.is_none() {
+            return None;
+        }
+        Some(CardinalitySettings {
+            window: self.window_duration(),
+            slot: self.slot(),
+            source_limit: self.cardinality_limit,
+            network_limit: self.cardinality_net_limit,
+            growth: self.cardinality_growth,
+            min_sources: self.cardinality_min_sources,
+            block_seconds: self.block_seconds,
+        })
+    }
+
+    // None unless one of the connection limits is set
+    pub fn connections(&self) -> Option<ConnectionSettings> {
+        if self.conn_half_open_limit.is_none() && self.conn_rst_ratio.is_none() && self.conn_teardown_limit.is_none() {
+            return None;
+        }
+        Some(ConnectionSettings {
+            window: self.window_duration(),
+            hello_timeout: Duration::from_millis(self.conn_hello_timeout_ms),
+            teardown: Duration::from_millis(self.conn_teardown_ms),
+            half_open_limit: self.conn_half_open_limit,
+            rst_ratio: self.conn_rst_ratio,
+            min_connections: self.conn_min_connections,
+            teardown_limit: self.conn_teardown_limit,
+            block_seconds: self.block_seconds,
+        })
+    }
+
+    // None unless novel ja3 alerts are enabled
+    pub fn novelty(&self) -> Option<NoveltySettings> {
+        self.known_ja3_file.as_ref().map(|path| NoveltySettings {
+            path: path.clone(),
+            training: Duration::from_secs(self.novel_training),
+            period: Duration::from_secs(self.novel_period),
+            threshold: self.novel_threshold,
+        })
+    }
+
+    pub fn whitelist(&self) -> Result<Whitelist, String> {
+        Ok(Whitelist::new(
+            Arc::new(self.parse_whitelist_networks()?),
+            Arc::new(self.parse_whitelist_ja3()?),
+        ))
+    }
+
+    pub fn parse_whitelist_networks(&self) -> Result<Vec<IpNetwork>, String> {
         self.whitelist_networks.split(',')
-            .filter_map(|s| s.trim().parse::<Ipv4Network>().ok())
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<IpNetwork>().map_err(|e| format!("invalid whitelist network '{}': {}", s, e)))
+            .collect()
+    }
+
+
+    // the key template, falling back to {ja3} or {ja3}-{src} depending on --agg-ip
+    pub fn key_template(&self) -> KeyTemplate {
+        match &self.key_template {
+            Some(template) => template.clone(),
+            None if self.agg_ip => KeyTemplate::parse(JA3_SRC_TEMPLATE).unwrap(),
+            None => KeyTemplate::parse(JA3_TEMPLATE).unwrap(),
+        }
+    }
+
+    // the keyspace names the alerts of the enabled detectors are sent in
+    fn reserved_keyspaces(&self) -> Vec<&'static str> {
+        [
+            (self.baseline().is_some(), BASELINE_KEYSPACE),
+            (self.cardinality().is_some(), CARDINALITY_KEYSPACE),
+            (self.novelty().is_some(), NOVEL_KEYSPACE),
+            (self.connections().is_some(), CONNECTIONS_KEYSPACE),
+        ].into_iter()
+            .filter_map(|(enabled, name)| enabled.then_some(name))
             .collect()
     }
 
+    // the threshold for the TCP control packets of a key, the handshake threshold unless given
+    pub fn control_threshold(&self) -> u64 {
+        self.control_threshold.unwrap_or(self.threshold)
+    }
+
+    // the keyspaces to track, a single default keyspace unless --keyspace was given
+    pub fn keyspaces(&self) -> Vec<Keyspace> {
+        if self.keyspace.is_empty() {
+            return vec![Keyspace::new(DEFAULT_KEYSPACE.to_string(), self.key_template(), self.threshold, self.control_threshold(), self.window_duration(), self.block_seconds, self.slot())];
+        }
+        self.keyspace.iter()
+            .map(|spec| Keyspace::new(
+                spec.name.clone(),
+                spec.template.clone(),
+                spec.threshold.unwrap_or(self.threshold),
+                spec.control_threshold.unwrap_or(self.control_threshold()),
+                spec.window.unwrap_or(self.window_duration()),
+                spec.block_seconds.unwrap_or(self.block_seconds),
+                self.slot(),
+            ))
+            .collect()
+    }
 
-    pub fn parse_whitelist_ja3(&self) -> Vec<String> {
+    // whitelisted ja3s must be md5 hex digests. None used to whitelist the packets without a ja3, which
+    // are now counted against the control threshold, so it is refused rather than silently ignored
+    pub fn parse_whitelist_ja3(&self) -> Result<Vec<String>, String> {
         if self.whitelist_ja3s.is_empty() {
             log::debug!("No whitelist ja3s provided");
-            Vec::new()
+            Ok(Vec::new())
         } else {
             self.whitelist_ja3s.split(',')
-                .map(|s| s.trim().to_string())
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    if s == "None" {
+                        Err("None is no longer a whitelist ja3: packets without a ja3 are counted against --control-threshold, raise it to count fewer of them".to_string())
+                    } else if s.len() == 32 && s.chars().all(|c| c.is_ascii_hexdigit()) {
+                        Ok(s.to_ascii_lowercase())
+                    } else {
+                        Err(format!("invalid whitelist ja3 '{}': expected a 32 character md5 hex digest", s))
+                    }
+                })
                 .collect()
         }
     }
 
 
-}
\ No newline at end of file
+}
+// the command line defaults, for tests to override only the fields they care about
+#[cfg(test)]
+impl Default for AppArgs {
+    fn default() -> Self {
+        AppArgs::parse_from(["susspekt"])
+    }
+}
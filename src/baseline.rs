@@ -0,0 +1,259 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use crate::alert::{Alert, AlertKind, Metric};
+use crate::bucket::Bucket;
+use crate::monitor::PacketEvent;
+
+/**
+ * Anomaly detection against a learned baseline, next to the fixed thresholds of the keyspaces. Every ja3
+ * learns its normal handshake count per --baseline-interval as an exponentially weighted mean and
+ * variance, and alerts when the count over the last interval is more than --baseline-sigma standard
+ * deviations above the mean. e.g. with a mean of 400 and a standard deviation of 50, sigma 4 alerts over
+ * 600 handshakes in the interval.
+ *
+ * A ja3 doesn't alert until it has learned for the --baseline-warmup, or below the --baseline-min-count.
+ * Once learned, an interval counts towards the baseline at most at the alert level, so an attack doesn't
+ * teach the baseline that it is normal.
+ */
+
+pub const BASELINE_KEYSPACE: &str = "baseline";
+
+// a ja3 not seen for this long is forgotten, and warms up again if it comes back
+const IDLE: Duration = Duration::from_secs(86400);
+
+// the most empty intervals folded in at once after a ja3 was quiet, the mean has decayed by then
+const MAX_CATCH_UP: u32 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BaselineSettings {
+    pub sigma: f64, // standard deviations above the mean to alert at
+    pub interval: Duration, // the count is measured and learned per interval
+    pub slot: Duration,
+    pub alpha: f64, // weight of the latest interval in the mean and variance
+    pub warmup: Duration,
+    pub min_count: u64,
+    pub block_seconds: u32,
+}
+
+struct Baseline {
+    bucket: Bucket, // the count over the last interval, and the evidence
+    mean: f64,
+    variance: f64,
+    learned: u64, // intervals folded into the mean
+    interval_start: SystemTime,
+    interval_count: u64, // count of the interval being learned
+}
+
+impl Baseline {
+    fn new(ja3: &str, ts: SystemTime, settings: &BaselineSettings) -> Self {
+        Baseline {
+            bucket: Bucket::new(ja3.to_string(), ts, settings.interval, settings.slot),
+            mean: 0.0,
+            variance: 0.0,
+            learned: 0,
+            interval_start: ts,
+            interval_count: 0,
+        }
+    }
+
+    // the standard deviation, at least that of a poisson count so a steady ja3 doesn't alert on noise
+    fn stddev(&self) -> f64 {
+        self.variance.sqrt().max(self.mean.sqrt()).max(1.0)
+    }
+
+    // the count above which the ja3 is anomalous
+    fn limit(&self, sigma: f64) -> f64 {
+        self.mean + sigma * self.stddev()
+    }
+
+    // fold a finished interval into the mean and variance
+    fn learn(&mut self, count: u64, settings: &BaselineSettings) {
+        if self.learned == 0 {
+            self.mean = count as f64;
+        } else {
+            let count = (count as f64).min(self.limit(settings.sigma));
+            let diff = count - self.mean;
+            let increment = settings.alpha * diff;
+            self.mean += increment;
+            self.variance = (1.0 - settings.alpha) * (self.variance + diff * increment);
+        }
+        self.learned += 1;
+    }
+
+    // learn the intervals which finished before ts, the quiet ones as zero
+    fn roll(&mut self, ts: SystemTime, settings: &BaselineSettings) {
+        let mut folded = 0;
+        while ts.duration_since(self.interval_start).is_ok_and(|elapsed| elapsed >= settings.interval) {
+            if folded == MAX_CATCH_UP {
+                self.interval_start = ts;
+                break;
+            }
+            self.learn(self.interval_count, settings);
+            self.interval_count = 0;
+            self.interval_start += settings.interval;
+            folded += 1;
+        }
+    }
+
+    fn warm(&self, ts: SystemTime, settings: &BaselineSettings) -> bool {
+        self.learned > 0 && ts.duration_since(self.bucket.first_ts).is_ok_and(|age| age >= settings.warmup)
+    }
+}
+
+pub(crate) struct BaselineDetector {
+    settings: BaselineSettings,
+    baselines: HashMap<String, Baseline>, // per ja3
+    last_cleanup: Option<SystemTime>,
+}
+
+impl BaselineDetector {
+    pub fn new(settings: BaselineSettings) -> Self {
+        BaselineDetector {
+            settings,
+            baselines: HashMap::new(),
+            last_cleanup: None,
+        }
+    }
+
+    // swap in reloaded settings, keeping what was learned
+    pub fn reconfigure(&mut self, settings: BaselineSettings) {
+        for baseline in self.baselines.values_mut() {
+            baseline.bucket.resize(settings.interval, settings.slot);
+        }
+        self.settings = settings;
+    }
+
+    // count a handshake of a ja3, and return an alert if its rate is anomalous
+    pub fn process(&mut self, ja3: &str, event: &PacketEvent) -> Option<Alert> {
+        self.cleanup(event.ts);
+        let settings = &self.settings;
+        let baseline = self.baselines.entry(ja3.to_string())
+            .or_insert_with(|| Baseline::new(ja3, event.ts, settings));
+
+        baseline.roll(event.ts, settings);
+        baseline.interval_count += 1;
+        baseline.bucket.update(event.ts);
+        baseline.bucket.observe(event.source, SocketAddr::new(event.destination, event.destination_port));
+
+        let count = baseline.bucket.rolling_window.sum();
+        let threshold = baseline.limit(settings.sigma).floor() as u64;
+        if !baseline.warm(event.ts, settings) || count < settings.min_count || count <= threshold {
+            return None;
+        }
+
+        if !baseline.bucket.should_realert(event.ts, settings.interval) {
+            return None;
+        }
+        baseline.bucket.last_alert_ts = Some(event.ts);
+
+        log::info!("Baseline violation, {} handshakes within {:?} for ja3: {}, mean: {:.1}, stddev: {:.1}",
+            count, settings.interval, ja3, baseline.mean, baseline.stddev());
+        Some(Alert {
+            kind: AlertKind::Block,
+            keyspace: BASELINE_KEYSPACE.to_string(),
+            metric: Metric::Handshake,
+            key: ja3.to_string(),
+            ja3: Some(ja3.to_string()),
+            source: event.source,
+            count,
+            threshold,
+            window: settings.interval,
+            block_seconds: settings.block_seconds,
+            ts: event.ts,
+            evidence: baseline.bucket.evidence(),
+        })
+    }
+
+    // forget the ja3s which went quiet, at most once an interval
+    fn cleanup(&mut self, ts: SystemTime) {
+        let last_cleanup = *self.last_cleanup.get_or_insert(ts);
+        if !ts.duration_since(last_cleanup).is_ok_and(|elapsed| elapsed >= self.settings.interval) {
+            return;
+        }
+        self.baselines.retain(|_, baseline| !ts.duration_since(baseline.bucket.last_ts).is_ok_and(|idle| idle > IDLE));
+        self.last_cleanup = Some(ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::{test_start, TEST_JA3 as JA3};
+
+    fn settings() -> BaselineSettings {
+        BaselineSettings {
+            sigma: 4.0,
+            interval: Duration::from_secs(10),
+            slot: Duration::from_secs(1),
+            alpha: 0.1,
+            warmup: Duration::from_secs(600),
+            min_count: 50,
+            block_seconds: 86400,
+        }
+    }
+
+    // send count handshakes spread over each second from start, returning the alerts
+    fn traffic(detector: &mut BaselineDetector, start: SystemTime, seconds: u64, per_second: u64) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for second in 0..seconds {
+            for n in 0..per_second {
+                let ts = start + Duration::from_secs(second) + Duration::from_millis(n * 1000 / per_second);
+                alerts.extend(detector.process(JA3, &PacketEvent::test(ts)));
+            }
+        }
+        alerts
+    }
+
+    #[test]
+    fn test_learns_and_alerts() {
+        let start = test_start();
+        let mut detector = BaselineDetector::new(settings());
+
+        // a burst during the warm-up doesn't alert
+        assert!(traffic(&mut detector, start, 300, 10).is_empty());
+        assert!(traffic(&mut detector, start + Duration::from_secs(300), 10, 100).is_empty());
+        assert!(traffic(&mut detector, start + Duration::from_secs(310), 600, 10).is_empty());
+
+        let baseline = &detector.baselines[JA3];
+        assert!((baseline.mean - 100.0).abs() < 5.0, "{}", baseline.mean);
+
+        // ten times the normal rate does, once per interval
+        let alerts = traffic(&mut detector, start + Duration::from_secs(910), 20, 100);
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].keyspace, BASELINE_KEYSPACE);
+        assert_eq!(alerts[0].key, JA3);
+        assert!(alerts[0].threshold >= 140 && alerts[0].threshold < 200, "{}", alerts[0].threshold);
+        assert!(alerts[0].count > alerts[0].threshold);
+
+        // the attack was clipped at the alert level, so the baseline barely moved
+        assert!(detector.baselines[JA3].mean < 120.0, "{}", detector.baselines[JA3].mean);
+    }
+
+    #[test]
+    fn test_rare_ja3() {
+        let start = test_start();
+        let mut detector = BaselineDetector::new(settings());
+
+        // one handshake a minute, then a handful at once, stays under the min count
+        for minute in 0..30 {
+            assert!(detector.process(JA3, &PacketEvent::test(start + Duration::from_secs(minute * 60))).is_none());
+        }
+        assert!(traffic(&mut detector, start + Duration::from_secs(1800), 1, 20).is_empty());
+    }
+
+    #[test]
+    fn test_cleanup() {
+        let start = test_start();
+        let mut detector = BaselineDetector::new(settings());
+        detector.process(JA3, &PacketEvent::test(start));
+        detector.process("e7d705a3286e19ea42f587b344ee6865", &PacketEvent::test(start + IDLE + Duration::from_secs(60)));
+        assert_eq!(detector.baselines.len(), 1);
+        assert!(detector.baselines.contains_key("e7d705a3286e19ea42f587b344ee6865"));
+    }
+}
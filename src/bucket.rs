@@ -2,64 +2,62 @@
 // Licensed under the MIT License (https://opensource.org/licenses/MIT)
 // This file may not be copied, modified, or distributed except according to those terms.
 
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use crate::alert::Evidence;
+
+// the most distinct sources and destinations a bucket keeps as evidence
+pub const MAX_EVIDENCE: usize = 16;
 
 // Define a struct 'Bucket' to represent a bucket in a rolling window time series analysis.
 pub(crate) struct Bucket {
+    pub first_ts: SystemTime, // capture time of the first packet of the bucket
     pub last_ts: SystemTime, // Timestamp of the last update to the bucket.
     // pub rolling_count: Vec<u16>, // A vector to hold counts for each second in a 5-minute rolling window.
     pub rolling_window: RollingWindow,
-    pub sum_count: u16, // Sum of counts over the current rolling window.
-    pub last_alert_ts: Option<Instant>, // Optional timestamp of the last alert. 'None' if no alert has been triggered.
-    pub window_size: usize, // the rolling window size
+    pub sum_count: u64, // Sum of counts over the current rolling window.
+    pub last_alert_ts: Option<SystemTime>, // Optional capture timestamp of the last alert. 'None' if no alert has been triggered.
+    pub last_over_ts: Option<SystemTime>, // capture time the bucket was last seen over its threshold
+    pub under_ts: Option<SystemTime>, // capture time it was first seen back under its threshold since then
+    pub sources: Vec<IpAddr>, // distinct sources seen, up to MAX_EVIDENCE
+    pub destinations: Vec<SocketAddr>, // distinct destinations seen, up to MAX_EVIDENCE
+    pub window: Duration, // the rolling window duration
+    pub slot: Duration, // the resolution of the rolling window
     // start_ts: SystemTime,
 }
 
 // Implementation of methods for the 'Bucket' struct.
 impl Bucket {
     // Constructor for a new 'Bucket'. Initializes the struct.
-    pub fn new(_ja3: String, current_ts: SystemTime, window_size: usize) -> Self {
+    pub fn new(_ja3: String, current_ts: SystemTime, window: Duration, slot: Duration) -> Self {
         Bucket {
+            first_ts: current_ts,
             last_ts: current_ts, // Set the last timestamp to the current timestamp.
             // rolling_count: vec![0; window_size], // Initialize rolling_count with 300 zeroes, representing a 5-minute window with 1-second intervals.
-            rolling_window: RollingWindow::new(window_size),
+            rolling_window: RollingWindow::new(window, slot),
             sum_count: 0, // Initialize sum_count to 0.
             last_alert_ts: None, // Initialize last_alert_ts to None, indicating no alerts have been triggered yet.
-            window_size, // window size
+            last_over_ts: None,
+            under_ts: None,
+            sources: Vec::new(),
+            destinations: Vec::new(),
+            window, // window duration
+            slot, // slot duration
             // start_ts: current_ts,
         }
     }
 
     // Updates the bucket with the current timestamp. This method adjusts the rolling window and count.
     pub fn update(&mut self, current_ts: SystemTime) {
-        // Calculate the elapsed time in seconds since the UNIX epoch for both timestamps
-        let last_ts_secs = match self.last_ts.duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => 0, // Handle the case where last_ts is earlier than UNIX_EPOCH
-        };
-
-        let current_ts_secs = match current_ts.duration_since(UNIX_EPOCH) {
-            Ok(n) => n.as_secs(),
-            Err(_) => 0, // Handle the case where current_ts is earlier than UNIX_EPOCH
-        };
-
-        // Calculate the number of seconds elapsed between the last and current timestamps
-        let elapsed_secs = if current_ts_secs > last_ts_secs {
-            current_ts_secs - last_ts_secs
-        } else {
-            0 // Handle case where current_ts is earlier than or equal to last_ts
-        };
-
-        log::debug!("elapsed_secs: {}", elapsed_secs);
-
-        // Calculate the index based on elapsed time
-        let current_idx = elapsed_secs as usize % self.window_size;
-        log::debug!("current_idx: {}", current_idx);
+        // Calculate the time elapsed between the last and current timestamps, zero if current_ts is earlier
+        let elapsed = current_ts.duration_since(self.last_ts).unwrap_or(Duration::ZERO);
+        log::debug!("elapsed: {:?}", elapsed);
 
         // Reset window if necessary
-        if elapsed_secs as usize >= self.window_size {
-            log::debug!("elapsed_secs > window_size, new window");
-            self.rolling_window = RollingWindow::new(self.window_size);
+        if elapsed >= self.window {
+            log::debug!("elapsed >= window, new window");
+            self.rolling_window = RollingWindow::new(self.window, self.slot);
         }
 
         // Update the rolling window
@@ -71,13 +69,69 @@ impl Bucket {
         self.last_ts = current_ts;
     }
 
+    /// change the window and slot duration, keeping the counts which still fit in it
+    pub fn resize(&mut self, window: Duration, slot: Duration) {
+        self.window = window;
+        self.slot = slot;
+        self.rolling_window.resize(window, slot);
+    }
+
     /// check the threshold
-    pub fn check_threshold(&self, threshold: u16) -> bool {
+    pub fn check_threshold(&self, threshold: u64) -> bool {
         // Sum the counts in the rolling window and check against the threshold
         log::debug!("checking threshold: {} > {}", self.rolling_window.sum(), threshold);
         self.rolling_window.sum() > threshold
     }
 
+    /// record the source and destination of a packet as evidence
+    pub fn observe(&mut self, source: IpAddr, destination: SocketAddr) {
+        if self.sources.len() < MAX_EVIDENCE && !self.sources.contains(&source) {
+            self.sources.push(source);
+        }
+        if self.destinations.len() < MAX_EVIDENCE && !self.destinations.contains(&destination) {
+            self.destinations.push(destination);
+        }
+    }
+
+    /// what the bucket saw, for an alert
+    pub fn evidence(&self) -> Evidence {
+        Evidence {
+            sources: self.sources.clone(),
+            destinations: self.destinations.clone(),
+            first_seen: Some(self.first_ts),
+            last_seen: Some(self.last_ts),
+            slot: self.slot,
+            histogram: self.rolling_window.window.iter().copied().collect(),
+            distinct_sources: None,
+            distinct_networks: None,
+        }
+    }
+
+    /// record whether the bucket was over its threshold at the capture time
+    pub fn track_threshold(&mut self, over: bool, current_ts: SystemTime) {
+        if over {
+            self.last_over_ts = Some(current_ts);
+            self.under_ts = None;
+        } else if self.last_over_ts.is_some() && self.under_ts.is_none() {
+            self.under_ts = Some(current_ts);
+        }
+    }
+
+    /// capture time since which the bucket has been back under its threshold, None if it never went over.
+    /// without packets to check it against, a quiet bucket is under once its last count has left the window
+    pub fn calm_since(&self) -> Option<SystemTime> {
+        let drained = self.last_over_ts? + self.window;
+        Some(self.under_ts.map_or(drained, |under_ts| under_ts.min(drained)))
+    }
+
+    /// whether an alert at the capture time is due, at most one per window
+    pub fn should_realert(&self, current_ts: SystemTime, window: Duration) -> bool {
+        self.last_alert_ts.is_none_or(|last_alert_ts| {
+            current_ts.duration_since(last_alert_ts)
+                .is_ok_and(|elapsed| elapsed > window)
+        })
+    }
+
 }
 
 #[cfg(test)]
@@ -95,18 +149,18 @@ mod tests {
     #[test]
     fn test_bucket_initialization() {
         let now = SystemTime::now();
-        let window_size = 300; // 5 minutes
-        let bucket = Bucket::new("some_ja3".to_string(), now, window_size);
+        let window = Duration::from_secs(300); // 5 minutes
+        let bucket = Bucket::new("some_ja3".to_string(), now, window, Duration::from_secs(1));
 
         assert_eq!(bucket.last_ts, now);
         assert_eq!(bucket.sum_count, 0);
         assert!(bucket.last_alert_ts.is_none());
-        assert_eq!(bucket.window_size, window_size);
+        assert_eq!(bucket.window, window);
     }
 
     #[test]
     fn test_update() {
-        let mut bucket = Bucket::new("test_ja3".to_string(), SystemTime::now(), 60);
+        let mut bucket = Bucket::new("test_ja3".to_string(), SystemTime::now(), Duration::from_secs(60), Duration::from_secs(1));
         let current_ts = SystemTime::now();
 
         bucket.update(current_ts);
@@ -116,7 +170,7 @@ mod tests {
 
     #[test]
     fn test_rolling_window_behavior() {
-        let mut bucket = Bucket::new("test_ja3".to_string(), SystemTime::now(), 60);
+        let mut bucket = Bucket::new("test_ja3".to_string(), SystemTime::now(), Duration::from_secs(60), Duration::from_secs(1));
         let start_ts = SystemTime::now();
 
         // Simulate updates over time
@@ -140,7 +194,7 @@ mod tests {
 
     #[test]
     fn test_threshold_check() {
-        let mut bucket = Bucket::new("test_ja3".to_string(), SystemTime::now(), 60);
+        let mut bucket = Bucket::new("test_ja3".to_string(), SystemTime::now(), Duration::from_secs(60), Duration::from_secs(1));
 
         // Update the bucket to exceed the threshold
         for _ in 0..100 {
@@ -149,4 +203,85 @@ mod tests {
 
         assert!(bucket.check_threshold(50)); // Assuming the threshold is 50
     }
+
+    #[test]
+    fn test_threshold_beyond_u16() {
+        let start_ts = SystemTime::now();
+        let mut bucket = Bucket::new("test_ja3".to_string(), start_ts, Duration::from_secs(60), Duration::from_secs(1));
+
+        // a busy ja3 well past the old u16 limit of 65535
+        for i in 0..100000u64 {
+            bucket.update(start_ts + Duration::from_millis(i / 2));
+        }
+
+        assert_eq!(bucket.rolling_window.sum(), 100000);
+        assert!(bucket.check_threshold(70000));
+        assert!(!bucket.check_threshold(100000));
+    }
+
+    #[test]
+    fn test_sub_second_window() {
+        let start_ts = SystemTime::now();
+        let mut bucket = Bucket::new("test_ja3".to_string(), start_ts, Duration::from_millis(200), Duration::from_millis(100));
+
+        // 500 handshakes in 200ms trip a threshold a one second slot would only see spread over the minute
+        for i in 0..500u64 {
+            bucket.update(start_ts + Duration::from_micros(i * 400));
+        }
+        assert!(bucket.check_threshold(400));
+
+        // a quiet 300ms later the burst has left the window
+        bucket.update(start_ts + Duration::from_millis(500));
+        assert_eq!(bucket.rolling_window.sum(), 1);
+    }
+
+    #[test]
+    fn test_calm_since() {
+        let start_ts = SystemTime::now();
+        let mut bucket = Bucket::new("test_ja3".to_string(), start_ts, Duration::from_secs(10), Duration::from_secs(1));
+        assert_eq!(bucket.calm_since(), None);
+
+        bucket.track_threshold(true, start_ts);
+        assert_eq!(bucket.calm_since(), Some(start_ts + Duration::from_secs(10)));
+
+        // seen back under before its counts drained
+        bucket.track_threshold(false, start_ts + Duration::from_secs(3));
+        bucket.track_threshold(false, start_ts + Duration::from_secs(4));
+        assert_eq!(bucket.calm_since(), Some(start_ts + Duration::from_secs(3)));
+
+        bucket.track_threshold(true, start_ts + Duration::from_secs(5));
+        assert_eq!(bucket.calm_since(), Some(start_ts + Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn test_evidence() {
+        let start_ts = SystemTime::now();
+        let mut bucket = Bucket::new("test_ja3".to_string(), start_ts, Duration::from_secs(60), Duration::from_secs(1));
+
+        for i in 0..40u8 {
+            let ts = start_ts + Duration::from_millis(i as u64 * 100);
+            bucket.update(ts);
+            bucket.observe(std::net::IpAddr::from([192, 168, 0, i % 20]), std::net::SocketAddr::from(([10, 0, 0, 1], 443)));
+        }
+
+        let evidence = bucket.evidence();
+        assert_eq!(evidence.sources.len(), crate::bucket::MAX_EVIDENCE);
+        assert_eq!(evidence.destinations, vec![std::net::SocketAddr::from(([10, 0, 0, 1], 443))]);
+        assert_eq!(evidence.first_seen, Some(start_ts));
+        assert_eq!(evidence.last_seen, Some(start_ts + Duration::from_millis(3900)));
+        assert_eq!(evidence.histogram.iter().map(|(_, count)| *count).collect::<Vec<_>>(), vec![10, 10, 10, 10]);
+    }
+
+    #[test]
+    fn test_should_realert() {
+        let start_ts = SystemTime::now();
+        let window = Duration::from_secs(60);
+        let mut bucket = Bucket::new("test_ja3".to_string(), start_ts, window, Duration::from_secs(1));
+        assert!(bucket.should_realert(start_ts, window));
+
+        // after an alert the next one is due once more than a window has passed
+        bucket.last_alert_ts = Some(start_ts);
+        assert!(!bucket.should_realert(start_ts + window, window));
+        assert!(bucket.should_realert(start_ts + window + Duration::from_millis(1), window));
+    }
 }
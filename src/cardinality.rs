@@ -0,0 +1,292 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use crate::alert::{Alert, AlertKind, Metric};
+use crate::bucket::Bucket;
+use crate::hyperloglog::HyperLogLog;
+use crate::monitor::PacketEvent;
+
+/**
+ * Botnets sharing one ja3 across thousands of addresses stay under the threshold of every {ja3}-{src}
+ * key, while a {ja3} key can't tell them from a popular browser. This tracks the distinct source
+ * addresses and networks, /24 for IPv4 and /48 for IPv6, of every ja3 per window, with HyperLogLog
+ * estimates so the memory stays bounded. A ja3 alerts when either count crosses its limit, or when its
+ * distinct sources grow by the --cardinality-growth factor over the previous window.
+ */
+
+pub const CARDINALITY_KEYSPACE: &str = "cardinality";
+
+// a ja3 not seen for this many windows is forgotten
+const IDLE_WINDOWS: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CardinalitySettings {
+    pub window: Duration,
+    pub slot: Duration,
+    pub source_limit: Option<u64>, // distinct sources in a window
+    pub network_limit: Option<u64>, // distinct /24 or /48 networks in a window
+    pub growth: Option<f64>, // factor over the distinct sources of the previous window
+    pub min_sources: u64, // least distinct sources for a growth alert
+    pub block_seconds: u32,
+}
+
+struct Cardinality {
+    bucket: Bucket, // the handshakes over the window, and the evidence
+    window_start: SystemTime,
+    sources: HyperLogLog,
+    networks: HyperLogLog,
+    source_count: u64, // the estimates, kept until a register changes
+    network_count: u64,
+    previous: Option<u64>, // distinct sources of the previous window
+}
+
+impl Cardinality {
+    fn new(ja3: &str, ts: SystemTime, settings: &CardinalitySettings) -> Self {
+        Cardinality {
+            bucket: Bucket::new(ja3.to_string(), ts, settings.window, settings.slot),
+            window_start: ts,
+            sources: HyperLogLog::new(),
+            networks: HyperLogLog::new(),
+            source_count: 0,
+            network_count: 0,
+            previous: None,
+        }
+    }
+
+    // start a new window once the current one is over. after a window without packets there is nothing to
+    // grow from, as for a new ja3
+    fn roll(&mut self, ts: SystemTime, window: Duration) {
+        let elapsed = match ts.duration_since(self.window_start) {
+            Ok(elapsed) if elapsed >= window => elapsed,
+            _ => return,
+        };
+        let windows = (elapsed.as_nanos() / window.as_nanos().max(1)) as u32;
+        self.previous = (windows == 1).then_some(self.source_count);
+        self.window_start += window * windows;
+        self.sources = HyperLogLog::new();
+        self.networks = HyperLogLog::new();
+        self.source_count = 0;
+        self.network_count = 0;
+    }
+
+    fn insert(&mut self, source: IpAddr) {
+        if self.sources.insert(&source) {
+            self.source_count = self.sources.estimate();
+        }
+        if self.networks.insert(&network(source)) {
+            self.network_count = self.networks.estimate();
+        }
+    }
+
+    // the count and limit of the first limit crossed, if any
+    fn violation(&self, settings: &CardinalitySettings) -> Option<(u64, u64)> {
+        if let Some(limit) = settings.source_limit.filter(|&limit| self.source_count > limit) {
+            return Some((self.source_count, limit));
+        }
+        if let Some(limit) = settings.network_limit.filter(|&limit| self.network_count > limit) {
+            return Some((self.network_count, limit));
+        }
+        let growth = settings.growth?;
+        let limit = (growth * self.previous?.max(1) as f64).floor() as u64;
+        if self.source_count >= settings.min_sources && self.source_count > limit {
+            return Some((self.source_count, limit));
+        }
+        None
+    }
+}
+
+// the /24 of an IPv4 address, or the /48 of an IPv6 address
+fn network(source: IpAddr) -> IpAddr {
+    match source {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        },
+        IpAddr::V6(v6) => {
+            let [a, b, c, ..] = v6.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        },
+    }
+}
+
+pub(crate) struct CardinalityDetector {
+    settings: CardinalitySettings,
+    ja3s: HashMap<String, Cardinality>,
+    last_cleanup: Option<SystemTime>,
+}
+
+impl CardinalityDetector {
+    pub fn new(settings: CardinalitySettings) -> Self {
+        CardinalityDetector {
+            settings,
+            ja3s: HashMap::new(),
+            last_cleanup: None,
+        }
+    }
+
+    // swap in reloaded settings, keeping the counts
+    pub fn reconfigure(&mut self, settings: CardinalitySettings) {
+        for cardinality in self.ja3s.values_mut() {
+            cardinality.bucket.resize(settings.window, settings.slot);
+        }
+        self.settings = settings;
+    }
+
+    // count the source of a handshake of a ja3, and return an alert if the ja3 is spread too wide
+    pub fn process(&mut self, ja3: &str, event: &PacketEvent) -> Option<Alert> {
+        self.cleanup(event.ts);
+        let settings = &self.settings;
+        let cardinality = self.ja3s.entry(ja3.to_string())
+            .or_insert_with(|| Cardinality::new(ja3, event.ts, settings));
+
+        cardinality.roll(event.ts, settings.window);
+        cardinality.insert(event.source);
+        cardinality.bucket.update(event.ts);
+        cardinality.bucket.observe(event.source, SocketAddr::new(event.destination, event.destination_port));
+
+        let (count, threshold) = cardinality.violation(settings)?;
+
+        if !cardinality.bucket.should_realert(event.ts, settings.window) {
+            return None;
+        }
+        cardinality.bucket.last_alert_ts = Some(event.ts);
+
+        log::info!("Cardinality violation, ja3: {} seen from {} sources in {} networks within {:?}, previous window: {:?}",
+            ja3, cardinality.source_count, cardinality.network_count, settings.window, cardinality.previous);
+        let mut evidence = cardinality.bucket.evidence();
+        evidence.distinct_sources = Some(cardinality.source_count);
+        evidence.distinct_networks = Some(cardinality.network_count);
+        Some(Alert {
+            kind: AlertKind::Block,
+            keyspace: CARDINALITY_KEYSPACE.to_string(),
+            metric: Metric::Handshake,
+            key: ja3.to_string(),
+            ja3: Some(ja3.to_string()),
+            source: event.source,
+            count,
+            threshold,
+            window: settings.window,
+            block_seconds: settings.block_seconds,
+            ts: event.ts,
+            evidence,
+        })
+    }
+
+    // forget the ja3s which went quiet, at most once a window
+    fn cleanup(&mut self, ts: SystemTime) {
+        let last_cleanup = *self.last_cleanup.get_or_insert(ts);
+        if !ts.duration_since(last_cleanup).is_ok_and(|elapsed| elapsed >= self.settings.window) {
+            return;
+        }
+        let idle = self.settings.window * IDLE_WINDOWS;
+        self.ja3s.retain(|_, cardinality| !ts.duration_since(cardinality.bucket.last_ts).is_ok_and(|elapsed| elapsed > idle));
+        self.last_cleanup = Some(ts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::{test_start, TEST_JA3 as JA3};
+
+    fn settings() -> CardinalitySettings {
+        CardinalitySettings {
+            window: Duration::from_secs(60),
+            slot: Duration::from_secs(1),
+            source_limit: None,
+            network_limit: None,
+            growth: None,
+            min_sources: 100,
+            block_seconds: 86400,
+        }
+    }
+
+
+    fn event(source: IpAddr, ts: SystemTime) -> PacketEvent {
+        PacketEvent { source, ..PacketEvent::test(ts) }
+    }
+
+    // handshakes from count distinct addresses in 10.0.0.0/8, spread over a second from start
+    fn botnet(detector: &mut CardinalityDetector, start: SystemTime, count: u32) -> Vec<Alert> {
+        (0..count)
+            .filter_map(|n| {
+                let ts = start + Duration::from_micros(n as u64 * 1_000_000 / count as u64);
+                detector.process(JA3, &event(IpAddr::V4(Ipv4Addr::from(0x0a000000 + n * 7)), ts))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_source_limit() {
+        let start = test_start();
+        let mut detector = CardinalityDetector::new(CardinalitySettings { source_limit: Some(1000), ..settings() });
+
+        // a popular browser from a few addresses many times over doesn't alert
+        for n in 0..5000u32 {
+            let source = IpAddr::V4(Ipv4Addr::new(192, 0, 2, (n % 50) as u8));
+            assert!(detector.process(JA3, &event(source, start + Duration::from_millis(n as u64))).is_none());
+        }
+
+        let alerts = botnet(&mut detector, start + Duration::from_secs(10), 2000);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].keyspace, CARDINALITY_KEYSPACE);
+        assert_eq!(alerts[0].key, JA3);
+        assert_eq!(alerts[0].threshold, 1000);
+        assert!(alerts[0].count > 1000 && alerts[0].count < 1200, "{}", alerts[0].count);
+        assert_eq!(alerts[0].evidence.distinct_sources, Some(alerts[0].count));
+    }
+
+    #[test]
+    fn test_network_limit() {
+        let start = test_start();
+        let mut detector = CardinalityDetector::new(CardinalitySettings { network_limit: Some(20), ..settings() });
+
+        // a thousand addresses in one /24 and a /48 are two networks
+        for n in 0..1000u32 {
+            let source = IpAddr::V4(Ipv4Addr::new(198, 51, 100, (n % 256) as u8));
+            assert!(detector.process(JA3, &event(source, start)).is_none());
+        }
+        assert!(detector.process(JA3, &event("2001:db8:1:2::7".parse().unwrap(), start)).is_none());
+        assert!(detector.process(JA3, &event("2001:db8:1:3::7".parse().unwrap(), start)).is_none());
+
+        let alerts: Vec<Alert> = (0..30u32)
+            .filter_map(|n| detector.process(JA3, &event(IpAddr::V4(Ipv4Addr::new(203, 0, n as u8, 1)), start)))
+            .collect();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].evidence.distinct_networks, Some(21));
+    }
+
+    #[test]
+    fn test_growth() {
+        let start = test_start();
+        let mut detector = CardinalityDetector::new(CardinalitySettings { growth: Some(3.0), ..settings() });
+
+        // no previous window to grow from, then steady
+        assert!(botnet(&mut detector, start, 200).is_empty());
+        assert!(botnet(&mut detector, start + Duration::from_secs(60), 200).is_empty());
+
+        // tripling the sources within the next window alerts
+        let alerts = botnet(&mut detector, start + Duration::from_secs(120), 1000);
+        assert_eq!(alerts.len(), 1);
+        assert!(alerts[0].threshold >= 570 && alerts[0].threshold <= 630, "{}", alerts[0].threshold);
+    }
+
+    #[test]
+    fn test_growth_after_quiet_window() {
+        let start = test_start();
+        let mut detector = CardinalityDetector::new(CardinalitySettings { growth: Some(3.0), ..settings() });
+
+        // a ja3 back after a quiet window has nothing to grow from, as a new one
+        assert!(botnet(&mut detector, start, 200).is_empty());
+        assert!(botnet(&mut detector, start + Duration::from_secs(120), 1000).is_empty());
+
+        // the window after that is the baseline again
+        let alerts = botnet(&mut detector, start + Duration::from_secs(180), 4000);
+        assert_eq!(alerts.len(), 1);
+    }
+}
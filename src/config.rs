@@ -0,0 +1,491 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::args::AppArgs;
+use crate::httpauth::Secret;
+use crate::keyspace::KeyspaceSpec;
+use crate::keytemplate::KeyTemplate;
+use crate::overrides::{Matcher, Override};
+use crate::sink::SinkConfig;
+
+/**
+ * The TOML config file given with --config. Every command line option can be set here, plus the
+ * structures flags can't express nicely. Options given on the command line override the file.
+ * See susspekt.example.toml
+ */
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub interface: Option<String>,
+    pub file: Option<String>,
+    pub threshold: Option<u64>,
+    pub control_threshold: Option<u64>,
+    pub window: Option<u64>,
+    pub window_ms: Option<u64>,
+    pub slot_ms: Option<u64>,
+    pub alert_url: Option<String>,
+    pub dry_run: Option<bool>,
+    pub alert_retries: Option<u32>,
+    pub alert_backoff_ms: Option<u64>,
+    pub alert_backoff_max_ms: Option<u64>,
+    pub alert_outbox: Option<String>,
+    pub alert_batch_size: Option<usize>,
+    pub alert_batch_delay_ms: Option<u64>,
+    pub alert_bearer_token: Option<Secret>,
+    pub alert_basic_auth: Option<Secret>,
+    pub alert_hmac_secret: Option<Secret>,
+    pub alert_client_cert: Option<String>,
+    pub alert_client_key: Option<String>,
+    pub alert_ca_cert: Option<String>,
+    pub block_seconds: Option<u32>,
+    pub release_cooldown: Option<u64>,
+    pub block_ladder: Option<Vec<u32>>,
+    pub offence_memory: Option<u64>,
+    pub baseline_sigma: Option<f64>,
+    pub baseline_interval: Option<u64>,
+    pub baseline_alpha: Option<f64>,
+    pub baseline_warmup: Option<u64>,
+    pub baseline_min_count: Option<u64>,
+    pub cardinality_limit: Option<u64>,
+    pub cardinality_net_limit: Option<u64>,
+    pub cardinality_growth: Option<f64>,
+    pub cardinality_min_sources: Option<u64>,
+    pub known_ja3_file: Option<String>,
+    pub novel_training: Option<u64>,
+    pub novel_period: Option<u64>,
+    pub novel_threshold: Option<u64>,
+    pub conn_half_open_limit: Option<u64>,
+    pub conn_rst_ratio: Option<f64>,
+    pub conn_teardown_limit: Option<u64>,
+    pub conn_min_connections: Option<u64>,
+    pub conn_hello_timeout_ms: Option<u64>,
+    pub conn_teardown_ms: Option<u64>,
+    pub whitelist_networks: Option<Vec<String>>,
+    pub whitelist_ja3s: Option<Vec<String>>,
+    pub log_create_buckets: Option<bool>,
+    pub agg_ip: Option<bool>,
+    pub agg_ipv6_prefix: Option<u8>,
+    pub key_template: Option<String>,
+    #[serde(default)]
+    pub keyspaces: Vec<FileKeyspace>,
+    #[serde(default)]
+    pub overrides: Vec<FileOverride>,
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FileKeyspace {
+    pub name: String,
+    pub template: String,
+    pub threshold: Option<u64>,
+    pub control_threshold: Option<u64>,
+    pub window: Option<u64>,
+    pub window_ms: Option<u64>,
+    pub block_seconds: Option<u32>,
+}
+
+// an override matches either a ja3 or a key prefix
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FileOverride {
+    pub ja3: Option<String>,
+    pub key_prefix: Option<String>,
+    pub threshold: Option<u64>,
+    pub window: Option<u64>,
+    pub window_ms: Option<u64>,
+    pub block_seconds: Option<u32>,
+}
+
+impl FileConfig {
+    pub fn load(path: &str) -> Result<FileConfig, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("unable to read config file '{}': {}", path, e))?;
+        Self::parse(&content).map_err(|e| format!("invalid config file '{}': {}", path, e))
+    }
+
+    pub fn parse(content: &str) -> Result<FileConfig, String> {
+        toml::from_str(content).map_err(|e| e.to_string())
+    }
+
+    // apply the file values to the args, skipping the ones that were given on the command line
+    pub fn apply<F: Fn(&str) -> bool>(self, args: &mut AppArgs, from_cli: F) -> Result<(), String> {
+        macro_rules! apply {
+            ($field:ident) => {
+                if let Some(value) = self.$field {
+                    if !from_cli(stringify!($field)) {
+                        args.$field = value;
+                    }
+                }
+            };
+            ($field:ident, Option) => {
+                if self.$field.is_some() && !from_cli(stringify!($field)) {
+                    args.$field = self.$field;
+                }
+            };
+        }
+
+        apply!(interface, Option);
+        apply!(file, Option);
+        apply!(threshold);
+        apply!(control_threshold, Option);
+        apply!(window);
+        apply!(window_ms, Option);
+        apply!(slot_ms);
+        apply!(alert_url);
+        apply!(dry_run);
+        apply!(alert_retries);
+        apply!(alert_backoff_ms);
+        apply!(alert_backoff_max_ms);
+        apply!(alert_outbox, Option);
+        apply!(alert_batch_size);
+        apply!(alert_batch_delay_ms);
+        apply!(alert_bearer_token, Option);
+        apply!(alert_basic_auth, Option);
+        apply!(alert_hmac_secret, Option);
+        apply!(alert_client_cert, Option);
+        apply!(alert_client_key, Option);
+        apply!(alert_ca_cert, Option);
+        apply!(block_seconds);
+        apply!(release_cooldown, Option);
+        apply!(block_ladder);
+        apply!(offence_memory);
+        apply!(baseline_sigma, Option);
+        apply!(baseline_interval);
+        apply!(baseline_alpha);
+        apply!(baseline_warmup);
+        apply!(baseline_min_count);
+        apply!(cardinality_limit, Option);
+        apply!(cardinality_net_limit, Option);
+        apply!(cardinality_growth, Option);
+        apply!(cardinality_min_sources);
+        apply!(known_ja3_file, Option);
+        apply!(novel_training);
+        apply!(novel_period);
+        apply!(novel_threshold, Option);
+        apply!(conn_half_open_limit, Option);
+        apply!(conn_rst_ratio, Option);
+        apply!(conn_teardown_limit, Option);
+        apply!(conn_min_connections);
+        apply!(conn_hello_timeout_ms);
+        apply!(conn_teardown_ms);
+        apply!(log_create_buckets, Option);
+        apply!(agg_ip);
+        apply!(agg_ipv6_prefix, Option);
+
+        // lists are joined back into their command line form, and validated along with it
+        if let Some(networks) = self.whitelist_networks {
+            if !from_cli("whitelist_networks") {
+                args.whitelist_networks = networks.join(",");
+            }
+        }
+        if let Some(ja3s) = self.whitelist_ja3s {
+            if !from_cli("whitelist_ja3s") {
+                args.whitelist_ja3s = ja3s.join(",");
+            }
+        }
+
+        if let Some(template) = self.key_template {
+            if !from_cli("key_template") {
+                args.key_template = Some(KeyTemplate::parse(&template)?);
+            }
+        }
+
+        if !self.keyspaces.is_empty() && !from_cli("keyspace") {
+            args.keyspace = self.keyspaces.into_iter()
+                .map(|keyspace| keyspace.into_spec())
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+
+        if !self.overrides.is_empty() && !from_cli("overrides") {
+            args.overrides = self.overrides.into_iter()
+                .map(|o| o.into_override())
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+
+        // sinks can't be given on the command line
+        if !self.sinks.is_empty() {
+            args.sinks = self.sinks;
+        }
+
+        Ok(())
+    }
+}
+
+// the window of a keyspace or override, window_ms takes precedence over window
+fn window(window: Option<u64>, window_ms: Option<u64>) -> Option<Duration> {
+    window_ms.map(Duration::from_millis).or(window.map(Duration::from_secs))
+}
+
+impl FileOverride {
+    fn into_override(self) -> Result<Override, String> {
+        let matcher = match (&self.ja3, &self.key_prefix) {
+            (Some(ja3), None) => Matcher::ja3(ja3)?,
+            (None, Some(prefix)) => Matcher::key_prefix(prefix)?,
+            _ => return Err("an override needs exactly one of ja3 or key_prefix".to_string()),
+        };
+        let parsed = Override {
+            matcher,
+            threshold: self.threshold,
+            window: window(self.window, self.window_ms),
+            block_seconds: self.block_seconds,
+        };
+        parsed.validate().map_err(|e| format!("{} in override for {:?}", e, parsed.matcher))?;
+        Ok(parsed)
+    }
+}
+
+impl FileKeyspace {
+    fn into_spec(self) -> Result<KeyspaceSpec, String> {
+        if self.name.trim().is_empty() {
+            return Err(format!("keyspace with template '{}' has an empty name", self.template));
+        }
+        let window = window(self.window, self.window_ms);
+        if window == Some(Duration::ZERO) {
+            return Err(format!("window must be greater than zero in keyspace '{}'", self.name));
+        }
+        let template = KeyTemplate::parse(&self.template)
+            .map_err(|e| format!("keyspace '{}': {}", self.name, e))?;
+        Ok(KeyspaceSpec {
+            name: self.name,
+            template,
+            threshold: self.threshold,
+            control_threshold: self.control_threshold,
+            window,
+            block_seconds: self.block_seconds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_args() -> AppArgs {
+        AppArgs {
+            config: Some("susspekt.toml".to_string()),
+            ..AppArgs::default()
+        }
+    }
+
+    const CONFIG: &str = r#"
+        interface = "eth0"
+        threshold = 200
+        window = 10
+        slot_ms = 100
+        dry_run = true
+        release_cooldown = 300
+        block_ladder = [600, 3600, 86400, 604800]
+        baseline_sigma = 4.5
+        baseline_warmup = 7200
+        whitelist_networks = ["10.0.0.0/8", "2001:db8::/32"]
+        whitelist_ja3s = ["579ccef312d18482fc42e2b822ca2430"]
+
+        [[keyspaces]]
+        name = "ja3"
+        template = "{ja3}"
+
+        [[keyspaces]]
+        name = "per_ip"
+        template = "{ja3}-{src}"
+        threshold = 50
+        block_seconds = 600
+
+        [[keyspaces]]
+        name = "burst"
+        template = "{src}"
+        threshold = 500
+        window_ms = 200
+
+        [[overrides]]
+        ja3 = "e7d705a3286e19ea42f587b344ee6865"
+        threshold = 5
+        block_seconds = 604800
+
+        [[overrides]]
+        key_prefix = "None-"
+        threshold = 50000
+
+        [[sinks]]
+        type = "http"
+        url = "http://localhost:8080/api/block/update"
+        retries = 5
+        outbox = "/var/lib/susspekt/outbox.jsonl"
+
+        [[sinks]]
+        name = "audit"
+        type = "file"
+        path = "/var/log/susspekt/alerts.jsonl"
+        keyspaces = ["per_ip"]
+        dry_run = false
+    "#;
+
+    #[test]
+    fn test_apply() {
+        let mut args = test_args();
+        FileConfig::parse(CONFIG).unwrap().apply(&mut args, |_| false).unwrap();
+        args.validate().unwrap();
+
+        assert_eq!(args.interface, Some("eth0".to_string()));
+        assert_eq!(args.threshold, 200);
+        assert_eq!(args.window, 10);
+        assert!(args.dry_run);
+        assert_eq!(args.release_cooldown(), Some(Duration::from_secs(300)));
+        assert_eq!(args.block_ladder, vec![600, 3600, 86400, 604800]);
+        assert_eq!(args.offence_memory, 604800);
+        let baseline = args.baseline().unwrap();
+        assert_eq!((baseline.sigma, baseline.interval, baseline.warmup), (4.5, Duration::from_secs(60), Duration::from_secs(7200)));
+        assert_eq!(args.parse_whitelist_networks().unwrap().len(), 2);
+        assert_eq!(args.parse_whitelist_ja3().unwrap(), vec!["579ccef312d18482fc42e2b822ca2430".to_string()]);
+
+        let keyspaces = args.keyspaces();
+        assert_eq!(keyspaces.len(), 3);
+        assert_eq!(keyspaces[0].threshold, 200);
+        assert_eq!(keyspaces[1].name, "per_ip");
+        assert_eq!(keyspaces[1].threshold, 50);
+        assert_eq!(keyspaces[1].window, Duration::from_secs(10));
+        assert_eq!(keyspaces[1].block_seconds, 600);
+        assert_eq!(keyspaces[2].window, Duration::from_millis(200));
+        assert_eq!(keyspaces[2].slot, Duration::from_millis(100));
+
+        assert_eq!(args.overrides.len(), 2);
+        assert_eq!(args.overrides[0].matcher, Matcher::Ja3("e7d705a3286e19ea42f587b344ee6865".to_string()));
+        assert_eq!(args.overrides[1].threshold, Some(50000));
+
+        assert_eq!(args.sinks.len(), 2);
+        assert_eq!(args.sinks[0].retries, Some(5));
+        assert_eq!(args.sinks[1].name(), "audit");
+        assert_eq!(args.sinks[1].filter().keyspaces, vec!["per_ip".to_string()]);
+    }
+
+    #[test]
+    fn test_cli_overrides_file() {
+        let mut args = test_args();
+        args.threshold = 5;
+        FileConfig::parse(CONFIG).unwrap()
+            .apply(&mut args, |id| id == "threshold" || id == "whitelist_networks")
+            .unwrap();
+
+        assert_eq!(args.threshold, 5);
+        assert_eq!(args.window, 10);
+        assert_eq!(args.whitelist_networks, "10.0.0.0/8, 192.168.0.0/16");
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        let err = FileConfig::parse("thresold = 10").unwrap_err();
+        assert!(err.contains("thresold"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse(r#"whitelist_networks = ["10.0.0.0/8", "10.0.0.300/24"]"#).unwrap()
+            .apply(&mut args, |_| false).unwrap();
+        let err = args.parse_whitelist_networks().unwrap_err();
+        assert!(err.contains("10.0.0.300/24"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse(r#"whitelist_ja3s = ["579ccef312d18482fc42e2b822ca243"]"#).unwrap()
+            .apply(&mut args, |_| false).unwrap();
+        let err = args.parse_whitelist_ja3().unwrap_err();
+        assert!(err.contains("579ccef312d18482fc42e2b822ca243"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse(r#"whitelist_ja3s = ["None"]"#).unwrap()
+            .apply(&mut args, |_| false).unwrap();
+        let err = args.parse_whitelist_ja3().unwrap_err();
+        assert!(err.contains("--control-threshold"), "{}", err);
+
+        let err = FileConfig::parse("[[keyspaces]]\nname = \"bad\"\ntemplate = \"{foo}\"").unwrap()
+            .apply(&mut args, |_| false).unwrap_err();
+        assert!(err.contains("keyspace 'bad'"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse("interface = \"eth0\"\n[[keyspaces]]\nname = \"a\"\ntemplate = \"{src}\"\n[[keyspaces]]\nname = \"a\"\ntemplate = \"{ja3}\"").unwrap()
+            .apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("keyspace name 'a' is used more than once"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse("interface = \"eth0\"\nconn_half_open_limit = 100\n[[keyspaces]]\nname = \"connections\"\ntemplate = \"{src}\"").unwrap()
+            .apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("'connections' is taken by the connections alerts"), "{}", err);
+        args.conn_half_open_limit = None;
+        args.validate().unwrap();
+
+        let err = FileConfig::parse("[[overrides]]\nja3 = \"abc\"\nthreshold = 5").unwrap()
+            .apply(&mut args, |_| false).unwrap_err();
+        assert!(err.contains("'abc'"), "{}", err);
+
+        let err = FileConfig::parse("[[overrides]]\nthreshold = 5").unwrap()
+            .apply(&mut args, |_| false).unwrap_err();
+        assert!(err.contains("exactly one of ja3 or key_prefix"), "{}", err);
+
+        let err = FileConfig::parse("[[sinks]]\ntype = \"file\"\npath = \"a.jsonl\"\nretry = 3").unwrap_err();
+        assert!(err.contains("retry"), "{}", err);
+
+        let mut args = test_args();
+        args.interface = Some("eth0".to_string());
+        FileConfig::parse("[[sinks]]\ntype = \"file\"\npath = \"a.jsonl\"\n[[sinks]]\ntype = \"file\"\npath = \"a.jsonl\"").unwrap()
+            .apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("shares its file"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse("interface = \"eth0\"\nblock_ladder = [600, 0]").unwrap().apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("block_ladder"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse("interface = \"eth0\"\nbaseline_sigma = 4\nbaseline_alpha = 1.5").unwrap().apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("baseline_alpha"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse("interface = \"eth0\"\ncardinality_growth = 0.5").unwrap().apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("cardinality_growth"), "{}", err);
+
+        let err = FileConfig::parse("alert_bearer_token = \"hunter2\"").unwrap_err();
+        assert!(err.contains("expected env:NAME or file:PATH"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse("interface = \"eth0\"\n[[sinks]]\ntype = \"http\"\nbearer_token = \"env:SUSSPEKT_TEST_UNSET_TOKEN\"").unwrap()
+            .apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("sink 'http'") && err.contains("SUSSPEKT_TEST_UNSET_TOKEN"), "{}", err);
+    }
+
+    #[test]
+    fn test_invalid_windows() {
+        let mut args = test_args();
+        FileConfig::parse("interface = \"eth0\"\nwindow_ms = 200").unwrap().apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("shorter than the slot"), "{}", err);
+
+        let mut args = test_args();
+        FileConfig::parse("interface = \"eth0\"\nwindow = 86400\nslot_ms = 100").unwrap().apply(&mut args, |_| false).unwrap();
+        let err = args.validate().unwrap_err();
+        assert!(err.contains("raise --slot-ms"), "{}", err);
+    }
+
+    #[test]
+    fn test_example_config() {
+        let mut args = test_args();
+        FileConfig::load("susspekt.example.toml").unwrap().apply(&mut args, |_| false).unwrap();
+        args.validate().unwrap();
+        assert_eq!(args.keyspaces().len(), 2);
+    }
+
+    #[test]
+    fn test_missing_input() {
+        let mut args = test_args();
+        FileConfig::parse("threshold = 10").unwrap().apply(&mut args, |_| false).unwrap();
+        assert!(args.validate().is_err());
+    }
+}
@@ -0,0 +1,435 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use crate::alert::{Alert, AlertKind, Evidence, Metric};
+use crate::bucket::MAX_EVIDENCE;
+use crate::monitor::PacketEvent;
+
+/**
+ * The state of the TCP connections of every source, from the SYN, FIN and RST packets and the
+ * ClientHellos. Scanners and TLS exhaustion attacks show up as connections which never get to a
+ * ClientHello, connections which end with a RST, and connections torn down right after the hello.
+ * Each is counted per source over the window, and alerts when it crosses its limit:
+ *
+ *   half_open   SYNs without a ClientHello within --conn-hello-timeout-ms, over --conn-half-open-limit
+ *   rst_ratio   the share of connections reset, over --conn-rst-ratio, from --conn-min-connections
+ *   teardown    connections closed within --conn-teardown-ms of the hello, over --conn-teardown-limit
+ *
+ * The alerts are keyed by {signal}-{source}, and block the source for the --block-seconds like a
+ * threshold violation. A NAT gateway or proxy whose clients reset lots of connections belongs in the
+ * --whitelist-networks. A SYN answering a pending SYN is taken for the SYN-ACK, so it doesn't count as
+ * a connection of the server.
+ */
+
+pub const CONNECTIONS_KEYSPACE: &str = "connections";
+
+// the most connections tracked at once, SYNs beyond it are not tracked
+const MAX_CONNECTIONS: usize = 100_000;
+
+// how often the pending connections are checked for a missing ClientHello
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+// a source not seen for this many windows is forgotten
+const IDLE_WINDOWS: u32 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionSettings {
+    pub window: Duration,
+    pub hello_timeout: Duration, // a SYN without a ClientHello for this long is half open
+    pub teardown: Duration, // a close this soon after the ClientHello is a teardown
+    pub half_open_limit: Option<u64>,
+    pub rst_ratio: Option<f64>,
+    pub min_connections: u64, // least connections in a window for a rst_ratio alert
+    pub teardown_limit: Option<u64>,
+    pub block_seconds: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Signal {
+    HalfOpen,
+    RstRatio,
+    Teardown,
+}
+
+impl Signal {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Signal::HalfOpen => "half_open",
+            Signal::RstRatio => "rst_ratio",
+            Signal::Teardown => "teardown",
+        }
+    }
+}
+
+struct Connection {
+    opened: SystemTime, // capture time of the SYN
+    hello: Option<SystemTime>, // capture time of the ClientHello
+}
+
+// the counts of a source over its current window
+struct SourceStats {
+    window_start: SystemTime,
+    last_ts: SystemTime,
+    connections: u64,
+    half_open: u64,
+    resets: u64,
+    teardowns: u64,
+    alerted: Vec<Signal>, // signals which alerted in the window
+    destinations: Vec<SocketAddr>, // distinct destinations, up to MAX_EVIDENCE
+}
+
+impl SourceStats {
+    fn new(ts: SystemTime) -> Self {
+        SourceStats {
+            window_start: ts,
+            last_ts: ts,
+            connections: 0,
+            half_open: 0,
+            resets: 0,
+            teardowns: 0,
+            alerted: Vec::new(),
+            destinations: Vec::new(),
+        }
+    }
+
+    // start a new window once the current one is over
+    fn roll(&mut self, ts: SystemTime, window: Duration) {
+        self.last_ts = self.last_ts.max(ts);
+        let elapsed = match ts.duration_since(self.window_start) {
+            Ok(elapsed) if elapsed >= window => elapsed,
+            _ => return,
+        };
+        let windows = (elapsed.as_nanos() / window.as_nanos().max(1)) as u32;
+        *self = SourceStats {
+            window_start: self.window_start + window * windows,
+            ..SourceStats::new(self.last_ts)
+        };
+    }
+
+    // the count and limit of a signal, if it crossed its limit
+    fn violation(&self, signal: Signal, settings: &ConnectionSettings) -> Option<(u64, u64)> {
+        match signal {
+            Signal::HalfOpen => settings.half_open_limit
+                .filter(|&limit| self.half_open > limit)
+                .map(|limit| (self.half_open, limit)),
+            Signal::RstRatio => settings.rst_ratio
+                .filter(|&ratio| self.connections >= settings.min_connections && self.resets as f64 > ratio * self.connections as f64)
+                .map(|ratio| (self.resets, (ratio * self.connections as f64).floor() as u64)),
+            Signal::Teardown => settings.teardown_limit
+                .filter(|&limit| self.teardowns > limit)
+                .map(|limit| (self.teardowns, limit)),
+        }
+    }
+}
+
+// the stats of a source, rolled forward to ts
+fn stats_for(sources: &mut HashMap<IpAddr, SourceStats>, source: IpAddr, ts: SystemTime, window: Duration) -> &mut SourceStats {
+    let stats = sources.entry(source).or_insert_with(|| SourceStats::new(ts));
+    stats.roll(ts, window);
+    stats
+}
+
+pub(crate) struct ConnectionTracker {
+    settings: ConnectionSettings,
+    connections: HashMap<(SocketAddr, SocketAddr), Connection>, // by client and server
+    sources: HashMap<IpAddr, SourceStats>,
+    last_sweep: Option<SystemTime>,
+    full: bool,
+}
+
+impl ConnectionTracker {
+    pub fn new(settings: ConnectionSettings) -> Self {
+        ConnectionTracker {
+            settings,
+            connections: HashMap::new(),
+            sources: HashMap::new(),
+            last_sweep: None,
+            full: false,
+        }
+    }
+
+    // swap in reloaded settings, keeping the connections and counts
+    pub fn reconfigure(&mut self, settings: ConnectionSettings) {
+        self.settings = settings;
+    }
+
+    // track the connection of a packet, and return the alerts of the sources which crossed a limit
+    pub fn process(&mut self, event: &PacketEvent) -> Vec<Alert> {
+        let mut alerts = self.sweep(event.ts);
+        let client = SocketAddr::new(event.source, event.source_port);
+        let server = SocketAddr::new(event.destination, event.destination_port);
+
+        if event.flags.syn {
+            self.open(client, server, event.ts);
+        } else if event.ja3.is_some() {
+            if let Some(connection) = self.connections.get_mut(&(client, server)) {
+                connection.hello.get_or_insert(event.ts);
+            }
+        }
+        if event.flags.fin || event.flags.rst {
+            alerts.extend(self.close(client, server, event.flags.rst, event.ts));
+        }
+        alerts
+    }
+
+    fn open(&mut self, client: SocketAddr, server: SocketAddr, ts: SystemTime) {
+        // a retransmitted SYN, or the SYN-ACK of a pending one
+        if self.connections.contains_key(&(client, server)) || self.connections.contains_key(&(server, client)) {
+            return;
+        }
+        if self.connections.len() >= MAX_CONNECTIONS {
+            if !self.full {
+                log::error!("Connections full at {}, new connections are not tracked until some close", MAX_CONNECTIONS);
+                self.full = true;
+            }
+            return;
+        }
+        self.full = false;
+        self.connections.insert((client, server), Connection { opened: ts, hello: None });
+
+        let stats = stats_for(&mut self.sources, client.ip(), ts, self.settings.window);
+        stats.connections += 1;
+        if stats.destinations.len() < MAX_EVIDENCE && !stats.destinations.contains(&server) {
+            stats.destinations.push(server);
+        }
+    }
+
+    // a FIN or RST from either side ends the connection
+    fn close(&mut self, from: SocketAddr, to: SocketAddr, reset: bool, ts: SystemTime) -> Vec<Alert> {
+        let (key, connection) = match self.connections.remove_entry(&(from, to)).or_else(|| self.connections.remove_entry(&(to, from))) {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        let client = key.0.ip();
+        let stats = stats_for(&mut self.sources, client, ts, self.settings.window);
+        if reset {
+            stats.resets += 1;
+        }
+        match connection.hello {
+            None => stats.half_open += 1,
+            Some(hello) if ts.duration_since(hello).map_or(true, |elapsed| elapsed <= self.settings.teardown) => stats.teardowns += 1,
+            Some(_) => {},
+        }
+        self.check(client, ts)
+    }
+
+    // alert for every signal of the source over its limit, once a window
+    fn check(&mut self, source: IpAddr, ts: SystemTime) -> Vec<Alert> {
+        let settings = &self.settings;
+        let stats = match self.sources.get_mut(&source) {
+            Some(stats) => stats,
+            None => return Vec::new(),
+        };
+        let mut alerts = Vec::new();
+        for signal in [Signal::HalfOpen, Signal::RstRatio, Signal::Teardown] {
+            let (count, threshold) = match stats.violation(signal, settings) {
+                Some(violation) if !stats.alerted.contains(&signal) => violation,
+                _ => continue,
+            };
+            stats.alerted.push(signal);
+            log::info!("Connection violation, {}: {} over: {} within {:?} for source: {}, connections: {}",
+                signal.as_str(), count, threshold, settings.window, source, stats.connections);
+            alerts.push(Alert {
+                kind: AlertKind::Block,
+                keyspace: CONNECTIONS_KEYSPACE.to_string(),
+                metric: Metric::Control,
+                key: format!("{}-{}", signal.as_str(), source),
+                ja3: None,
+                source,
+                count,
+                threshold,
+                window: settings.window,
+                block_seconds: settings.block_seconds,
+                ts,
+                evidence: Evidence {
+                    sources: vec![source],
+                    destinations: stats.destinations.clone(),
+                    first_seen: Some(stats.window_start),
+                    last_seen: Some(stats.last_ts),
+                    ..Evidence::default()
+                },
+            });
+        }
+        alerts
+    }
+
+    // count the SYNs which never got a ClientHello, and forget the established connections and the
+    // sources which went quiet
+    fn sweep(&mut self, ts: SystemTime) -> Vec<Alert> {
+        let last_sweep = *self.last_sweep.get_or_insert(ts);
+        if ts.duration_since(last_sweep).map_or(true, |elapsed| elapsed < SWEEP_INTERVAL) {
+            return Vec::new();
+        }
+        self.last_sweep = Some(ts);
+
+        let (hello_timeout, window) = (self.settings.hello_timeout, self.settings.window);
+        let mut half_open = Vec::new();
+        self.connections.retain(|(client, _), connection| {
+            let (since, timeout) = match connection.hello {
+                Some(hello) => (hello, window),
+                None => (connection.opened, hello_timeout),
+            };
+            if ts.duration_since(since).map_or(true, |elapsed| elapsed < timeout) {
+                return true;
+            }
+            if connection.hello.is_none() {
+                half_open.push(client.ip());
+            }
+            false
+        });
+
+        let mut alerts = Vec::new();
+        for source in half_open.iter() {
+            stats_for(&mut self.sources, *source, ts, window).half_open += 1;
+        }
+        half_open.sort();
+        half_open.dedup();
+        for source in half_open {
+            alerts.extend(self.check(source, ts));
+        }
+
+        let idle = window * IDLE_WINDOWS;
+        self.sources.retain(|_, stats| ts.duration_since(stats.last_ts).map_or(true, |elapsed| elapsed <= idle));
+        alerts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::TcpFlags;
+    use crate::monitor::test_start;
+
+    const SERVER: &str = "192.168.0.1";
+
+    fn settings() -> ConnectionSettings {
+        ConnectionSettings {
+            window: Duration::from_secs(60),
+            hello_timeout: Duration::from_secs(5),
+            teardown: Duration::from_secs(1),
+            half_open_limit: Some(20),
+            rst_ratio: Some(0.5),
+            min_connections: 20,
+            teardown_limit: Some(20),
+            block_seconds: 86400,
+        }
+    }
+
+    fn packet(source: &str, source_port: u16, destination: &str, destination_port: u16, ts: SystemTime) -> PacketEvent {
+        PacketEvent {
+            ja3: None,
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+            source_port,
+            destination_port,
+            ..PacketEvent::test(ts)
+        }
+    }
+
+    fn syn(port: u16, ts: SystemTime) -> PacketEvent {
+        PacketEvent { flags: TcpFlags { syn: true, ..TcpFlags::default() }, ..packet("8.8.8.8", port, SERVER, 443, ts) }
+    }
+
+    fn syn_ack(port: u16, ts: SystemTime) -> PacketEvent {
+        PacketEvent { flags: TcpFlags { syn: true, ..TcpFlags::default() }, ..packet(SERVER, 443, "8.8.8.8", port, ts) }
+    }
+
+    fn hello(port: u16, ts: SystemTime) -> PacketEvent {
+        PacketEvent { ja3: Some("579ccef312d18482fc42e2b822ca2430".to_string()), ..packet("8.8.8.8", port, SERVER, 443, ts) }
+    }
+
+    fn fin(port: u16, ts: SystemTime) -> PacketEvent {
+        PacketEvent { flags: TcpFlags { fin: true, ..TcpFlags::default() }, ..packet("8.8.8.8", port, SERVER, 443, ts) }
+    }
+
+    fn rst(port: u16, ts: SystemTime) -> PacketEvent {
+        PacketEvent { flags: TcpFlags { rst: true, ..TcpFlags::default() }, ..packet(SERVER, 443, "8.8.8.8", port, ts) }
+    }
+
+    #[test]
+    fn test_normal_connections() {
+        let start = test_start();
+        let mut tracker = ConnectionTracker::new(settings());
+        let mut alerts = Vec::new();
+        for n in 0..100u16 {
+            let ts = start + Duration::from_millis(n as u64 * 100);
+            alerts.extend(tracker.process(&syn(40000 + n, ts)));
+            alerts.extend(tracker.process(&syn_ack(40000 + n, ts)));
+            alerts.extend(tracker.process(&hello(40000 + n, ts)));
+            alerts.extend(tracker.process(&fin(40000 + n, ts + Duration::from_secs(5))));
+        }
+        alerts.extend(tracker.process(&packet("8.8.4.4", 50000, SERVER, 443, start + Duration::from_secs(30))));
+        assert!(alerts.is_empty());
+
+        let stats = &tracker.sources[&"8.8.8.8".parse::<IpAddr>().unwrap()];
+        assert_eq!((stats.connections, stats.half_open, stats.teardowns), (100, 0, 0));
+        assert!(!tracker.sources.contains_key(&SERVER.parse::<IpAddr>().unwrap()));
+        assert!(tracker.connections.is_empty());
+    }
+
+    #[test]
+    fn test_half_open() {
+        let start = test_start();
+        let mut tracker = ConnectionTracker::new(settings());
+        let mut alerts = Vec::new();
+        for n in 0..30u16 {
+            alerts.extend(tracker.process(&syn(40000 + n, start + Duration::from_millis(n as u64))));
+        }
+        assert!(alerts.is_empty());
+
+        // found once the hello timeout has passed
+        alerts.extend(tracker.process(&packet("8.8.4.4", 50000, SERVER, 443, start + Duration::from_secs(6))));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].keyspace, CONNECTIONS_KEYSPACE);
+        assert_eq!(alerts[0].key, "half_open-8.8.8.8");
+        assert_eq!((alerts[0].count, alerts[0].threshold), (30, 20));
+        assert_eq!(alerts[0].evidence.destinations, vec![SocketAddr::new(SERVER.parse().unwrap(), 443)]);
+
+        // once per window
+        for n in 0..30u16 {
+            alerts.extend(tracker.process(&syn(41000 + n, start + Duration::from_secs(7))));
+        }
+        alerts.extend(tracker.process(&packet("8.8.4.4", 50000, SERVER, 443, start + Duration::from_secs(13))));
+        assert_eq!(alerts.len(), 1);
+    }
+
+    #[test]
+    fn test_rst_ratio() {
+        let start = test_start();
+        let mut tracker = ConnectionTracker::new(settings());
+        let mut alerts = Vec::new();
+        for n in 0..20u16 {
+            let ts = start + Duration::from_millis(n as u64 * 100);
+            alerts.extend(tracker.process(&syn(40000 + n, ts)));
+            alerts.extend(tracker.process(&hello(40000 + n, ts)));
+            alerts.extend(tracker.process(&rst(40000 + n, ts + Duration::from_secs(3))));
+        }
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].key, "rst_ratio-8.8.8.8");
+        assert_eq!((alerts[0].count, alerts[0].threshold), (20, 10));
+
+        // the connection signals block the source, as the threshold violations do
+        assert_eq!((alerts[0].kind, alerts[0].block_seconds), (AlertKind::Block, 86400));
+    }
+
+    #[test]
+    fn test_teardown() {
+        let start = test_start();
+        let mut tracker = ConnectionTracker::new(ConnectionSettings { rst_ratio: None, ..settings() });
+        let mut alerts = Vec::new();
+        for n in 0..25u16 {
+            let ts = start + Duration::from_millis(n as u64 * 100);
+            alerts.extend(tracker.process(&syn(40000 + n, ts)));
+            alerts.extend(tracker.process(&hello(40000 + n, ts)));
+            alerts.extend(tracker.process(&fin(40000 + n, ts + Duration::from_millis(10))));
+        }
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].key, "teardown-8.8.8.8");
+        assert_eq!((alerts[0].count, alerts[0].threshold), (21, 20));
+    }
+}
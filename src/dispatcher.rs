@@ -0,0 +1,137 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use tokio::sync::mpsc::{self, Sender};
+use tokio::task::JoinHandle;
+
+use crate::alert::Alert;
+use crate::sink::{AlertSink, SinkFilter};
+
+/**
+ * Fans the alerts out to the sinks. Every sink runs in its own task behind its own channel, so a sink
+ * which is retrying against a dead endpoint doesn't hold up the others.
+ */
+
+const SINK_BUFFER_SIZE: usize = 1024;
+
+struct Route {
+    name: String,
+    filter: SinkFilter,
+    tx: Sender<Alert>,
+}
+
+pub struct Dispatcher {
+    routes: Vec<Route>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Dispatcher {
+            routes: Vec::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, mut sink: Box<dyn AlertSink>, filter: SinkFilter) {
+        let name = sink.name().to_string();
+        let (tx, mut rx) = mpsc::channel::<Alert>(SINK_BUFFER_SIZE);
+        let task = tokio::spawn(async move {
+            sink.start().await;
+            let mut tick = tokio::time::interval(sink.tick_interval());
+            loop {
+                tokio::select! {
+                    alert = rx.recv() => {
+                        let alert = match alert {
+                            Some(alert) => alert,
+                            None => {
+                                sink.stop().await;
+                                break;
+                            },
+                        };
+                        if let Err(e) = sink.send(&alert).await {
+                            log::error!("Sink: {} failed to deliver alert for key: {}, {}", sink.name(), alert.key, e);
+                        }
+                    },
+                    _ = tick.tick() => sink.tick().await,
+                }
+            }
+        });
+        self.routes.push(Route { name, filter, tx });
+        self.tasks.push(task);
+    }
+
+    // pass the alert to every sink whose filter it passes
+    pub async fn dispatch(&self, alert: Alert) {
+        for route in self.routes.iter().filter(|route| route.filter.matches(&alert)) {
+            if let Err(e) = route.tx.send(alert.clone()).await {
+                log::error!("Sink: {} is gone, dropping alert for key: {}", route.name, e.0.key);
+            }
+        }
+    }
+
+    // close the sink channels and wait for the sinks to drain
+    pub async fn close(self) {
+        drop(self.routes);
+        for task in self.tasks {
+            if let Err(e) = task.await {
+                log::error!("Sink task panicked: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+    use std::time::SystemTime;
+
+    struct CollectingSink {
+        name: String,
+        alerts: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl AlertSink for CollectingSink {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        async fn send(&mut self, alert: &Alert) -> Result<(), String> {
+            if alert.key == "fail" {
+                return Err("refused".to_string());
+            }
+            self.alerts.lock().unwrap().push(format!("{}/{}", alert.keyspace, alert.key));
+            Ok(())
+        }
+    }
+
+    fn alert(keyspace: &str, key: &str) -> Alert {
+        Alert { keyspace: keyspace.to_string(), ..Alert::test(key, SystemTime::now()) }
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let all = Arc::new(Mutex::new(Vec::new()));
+        let per_ip = Arc::new(Mutex::new(Vec::new()));
+
+        let mut dispatcher = Dispatcher::new();
+        dispatcher.add(Box::new(CollectingSink { name: "all".to_string(), alerts: all.clone() }), SinkFilter::default());
+        dispatcher.add(
+            Box::new(CollectingSink { name: "per_ip".to_string(), alerts: per_ip.clone() }),
+            SinkFilter { keyspaces: vec!["per_ip".to_string()], key_prefixes: Vec::new() },
+        );
+
+        dispatcher.dispatch(alert("default", "fail")).await;
+        dispatcher.dispatch(alert("default", "a")).await;
+        dispatcher.dispatch(alert("per_ip", "b")).await;
+        dispatcher.close().await;
+
+        // a failing alert doesn't stop the sink
+        assert_eq!(*all.lock().unwrap(), vec!["default/a".to_string(), "per_ip/b".to_string()]);
+        assert_eq!(*per_ip.lock().unwrap(), vec!["per_ip/b".to_string()]);
+    }
+}
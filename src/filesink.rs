@@ -0,0 +1,117 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::alert::Alert;
+use crate::retry::{self, RetryPolicy};
+use crate::sink::{AlertSink, SinkConfig};
+
+/**
+ * A local audit trail of the alerts, appended to a file as JSON lines.
+ */
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    ts: String, // capture time of the packet which tripped the threshold, RFC 3339
+    event: &'static str, // block, release or novel
+    metric: &'static str, // handshake or control
+    keyspace: &'a str,
+    key: &'a str,
+    window_ms: u128,
+    block_seconds: u32,
+}
+
+pub struct FileSink {
+    name: String,
+    path: String,
+    dry_run: bool,
+    retry: RetryPolicy,
+    file: Option<File>, // opened on the first write, and reopened after a failed one
+}
+
+impl FileSink {
+    pub fn new(config: &SinkConfig, dry_run: bool, retry: RetryPolicy) -> Self {
+        FileSink {
+            name: config.name(),
+            path: config.path.clone().unwrap_or_default(),
+            dry_run,
+            retry,
+            file: None,
+        }
+    }
+
+    fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        let result = self.file.as_mut().map_or(Ok(()), |file| writeln!(file, "{}", line));
+        if result.is_err() {
+            self.file = None;
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl AlertSink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&mut self, alert: &Alert) -> Result<(), String> {
+        let record = AuditRecord {
+            ts: DateTime::<Utc>::from(alert.ts).to_rfc3339_opts(SecondsFormat::Millis, true),
+            event: alert.kind.as_str(),
+            metric: alert.metric.as_str(),
+            keyspace: &alert.keyspace,
+            key: &alert.key,
+            window_ms: alert.window.as_millis(),
+            block_seconds: alert.block_seconds,
+        };
+        let line = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+
+        if self.dry_run {
+            log::info!("DryRun, not writing to {}: {}", self.path, line);
+            return Ok(());
+        }
+
+        let what = format!("writing alert for key: {} to {}", alert.key, self.path);
+        let retry = self.retry;
+        retry::with_retries(&retry, &what, || std::future::ready(self.write(&line))).await
+            .map_err(|e| format!("unable to write to {}: {}", self.path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[tokio::test]
+    async fn test_send() {
+        let path = std::env::temp_dir().join(format!("susspekt-audit-test-{}.jsonl", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let config: SinkConfig = toml::from_str(&format!("type = \"file\"\npath = {:?}", path)).unwrap();
+        let retry = RetryPolicy { retries: 0, backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+        let mut sink = FileSink::new(&config, false, retry);
+
+        let alert = Alert::test("579ccef312d18482fc42e2b822ca2430", UNIX_EPOCH + Duration::from_millis(1701076465288));
+        sink.send(&alert).await.unwrap();
+        sink.send(&Alert { ts: SystemTime::now(), ..alert.clone() }).await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], r#"{"ts":"2023-11-27T09:14:25.288Z","event":"block","metric":"handshake","keyspace":"default","key":"579ccef312d18482fc42e2b822ca2430","window_ms":60000,"block_seconds":86400}"#);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
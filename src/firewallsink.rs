@@ -0,0 +1,383 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::process::Stdio;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use ipnetwork::IpNetwork;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::address::{key_address, network_string};
+use crate::alert::{Alert, AlertKind};
+use crate::retry::{self, RetryPolicy};
+use crate::sink::AlertSink;
+
+/**
+ * Blocks the offending address on this box, as a set element with a timeout of the block time. The
+ * address is taken from the end of the key, e.g: {ja3}-{src} or {ja3}-{src_net/24}, keys without one
+ * such as a bare {ja3} are skipped. IPv4 goes to `set`, IPv6 to `set6`. A release removes the element.
+ *
+ *   nftables: nft -f - with "add element inet filter susspekt4 { 1.2.3.4 timeout 86400s }"
+ *   ipset:    ipset add susspekt4 1.2.3.4 timeout 86400 -exist
+ *
+ * The sets are created on startup if missing, and with a state file the blocks which are still due
+ * are re-applied, e.g. after a reboot flushed the kernel sets.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Nftables,
+    Ipset,
+}
+
+// where the blocks go, and what runs them
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirewallSettings {
+    pub backend: Backend,
+    pub set: String,
+    pub set6: Option<String>,
+    pub family: String, // nftables
+    pub table: String, // nftables
+    pub command: Option<String>, // the nft or ipset binary, found on the PATH by default
+    pub state: Option<String>, // blocks and their expiry, re-applied on startup
+}
+
+// a command to run, printed instead in dry-run mode
+#[derive(Debug, Clone, PartialEq)]
+struct FirewallCommand {
+    program: String,
+    args: Vec<String>,
+    stdin: Option<String>,
+}
+
+impl fmt::Display for FirewallCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.program, self.args.join(" "))?;
+        if let Some(stdin) = &self.stdin {
+            write!(f, " <<< '{}'", stdin.trim_end().replace('\n', "; "))?;
+        }
+        Ok(())
+    }
+}
+
+pub struct FirewallSink {
+    name: String,
+    settings: FirewallSettings,
+    dry_run: bool,
+    retry: RetryPolicy,
+    blocks: BTreeMap<String, u64>, // set element to the unix time its block expires
+}
+
+impl FirewallSink {
+    pub fn new(name: String, settings: FirewallSettings, dry_run: bool, retry: RetryPolicy) -> Self {
+        FirewallSink {
+            name,
+            settings,
+            dry_run,
+            retry,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    fn program(&self) -> String {
+        self.settings.command.clone().unwrap_or_else(|| match self.settings.backend {
+            Backend::Nftables => "nft".to_string(),
+            Backend::Ipset => "ipset".to_string(),
+        })
+    }
+
+    // the set for an element, None for IPv6 without a set6
+    fn set_for(&self, network: &IpNetwork) -> Option<&str> {
+        match network {
+            IpNetwork::V4(_) => Some(self.settings.set.as_str()),
+            IpNetwork::V6(_) => self.settings.set6.as_deref(),
+        }
+    }
+
+    // the commands creating the sets, if they don't exist
+    fn ensure_commands(&self) -> Vec<FirewallCommand> {
+        let mut sets = vec![(self.settings.set.as_str(), false)];
+        if let Some(set6) = &self.settings.set6 {
+            sets.push((set6.as_str(), true));
+        }
+        match self.settings.backend {
+            Backend::Nftables => {
+                let mut script = format!("add table {} {}\n", self.settings.family, self.settings.table);
+                for (set, v6) in sets {
+                    script.push_str(&format!("add set {} {} {} {{ type {}; flags interval, timeout; }}\n",
+                        self.settings.family, self.settings.table, set, if v6 { "ipv6_addr" } else { "ipv4_addr" }));
+                }
+                vec![self.nft(script)]
+            },
+            Backend::Ipset => sets.into_iter()
+                .map(|(set, v6)| FirewallCommand {
+                    program: self.program(),
+                    args: ["create", set, "hash:net", "family", if v6 { "inet6" } else { "inet" }, "timeout", "0", "-exist"]
+                        .iter().map(|s| s.to_string()).collect(),
+                    stdin: None,
+                })
+                .collect(),
+        }
+    }
+
+    // the command blocking an element for timeout seconds, refreshing the timeout if it is already blocked
+    fn block_command(&self, set: &str, element: &str, timeout: u64) -> FirewallCommand {
+        match self.settings.backend {
+            Backend::Nftables => {
+                // add, delete and add again in one transaction, as a plain add keeps the old timeout
+                let (family, table) = (&self.settings.family, &self.settings.table);
+                self.nft(format!("add element {family} {table} {set} {{ {element} }}\n\
+                    delete element {family} {table} {set} {{ {element} }}\n\
+                    add element {family} {table} {set} {{ {element} timeout {timeout}s }}\n"))
+            },
+            Backend::Ipset => FirewallCommand {
+                program: self.program(),
+                args: vec!["add".to_string(), set.to_string(), element.to_string(), "timeout".to_string(), timeout.to_string(), "-exist".to_string()],
+                stdin: None,
+            },
+        }
+    }
+
+    // the command lifting the block of an element, whether or not it is still in the set
+    fn release_command(&self, set: &str, element: &str) -> FirewallCommand {
+        match self.settings.backend {
+            Backend::Nftables => {
+                let (family, table) = (&self.settings.family, &self.settings.table);
+                self.nft(format!("add element {family} {table} {set} {{ {element} }}\n\
+                    delete element {family} {table} {set} {{ {element} }}\n"))
+            },
+            Backend::Ipset => FirewallCommand {
+                program: self.program(),
+                args: vec!["del".to_string(), set.to_string(), element.to_string(), "-exist".to_string()],
+                stdin: None,
+            },
+        }
+    }
+
+    fn nft(&self, script: String) -> FirewallCommand {
+        FirewallCommand {
+            program: self.program(),
+            args: vec!["-f".to_string(), "-".to_string()],
+            stdin: Some(script),
+        }
+    }
+
+    async fn run(&self, command: &FirewallCommand) -> Result<(), String> {
+        if self.dry_run {
+            log::info!("DryRun, sink: {} would run: {}", self.name, command);
+            return Ok(());
+        }
+        let what = format!("running: {}", command);
+        retry::with_retries(&self.retry, &what, || execute(command)).await
+    }
+
+    async fn block(&mut self, network: IpNetwork, timeout: u64) -> Result<(), String> {
+        let set = match self.set_for(&network) {
+            Some(set) => set.to_string(),
+            None => {
+                log::warn!("Sink: {} has no set6, not blocking: {}", self.name, network);
+                return Ok(());
+            },
+        };
+        let element = network_string(&network);
+        self.run(&self.block_command(&set, &element, timeout)).await?;
+        self.blocks.insert(element, now() + timeout);
+        self.persist();
+        Ok(())
+    }
+
+    async fn release(&mut self, network: IpNetwork) -> Result<(), String> {
+        let set = match self.set_for(&network) {
+            Some(set) => set.to_string(),
+            None => return Ok(()),
+        };
+        let element = network_string(&network);
+        self.run(&self.release_command(&set, &element)).await?;
+        self.blocks.remove(&element);
+        self.persist();
+        Ok(())
+    }
+
+    fn load(&mut self) {
+        if let Some(path) = &self.settings.state {
+            match std::fs::read_to_string(path) {
+                Ok(content) => match serde_json::from_str(&content) {
+                    Ok(blocks) => self.blocks = blocks,
+                    Err(e) => log::error!("Unable to parse firewall state: {}, {}", path, e),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => log::error!("Unable to read firewall state: {}, {}", path, e),
+            }
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(path) = &self.settings.state {
+            let tmp = format!("{}.tmp", path);
+            let result = serde_json::to_string(&self.blocks)
+                .map_err(std::io::Error::from)
+                .and_then(|json| std::fs::write(&tmp, json))
+                .and_then(|_| std::fs::rename(&tmp, path));
+            if let Err(e) = result {
+                log::error!("Unable to write firewall state: {}, {}", path, e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AlertSink for FirewallSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    // create the sets and re-apply the blocks which are still due
+    async fn start(&mut self) {
+        for command in self.ensure_commands() {
+            if let Err(e) = self.run(&command).await {
+                log::error!("Sink: {} unable to create its sets, {}", self.name, e);
+            }
+        }
+
+        self.load();
+        let now = now();
+        let due: Vec<(String, u64)> = std::mem::take(&mut self.blocks).into_iter()
+            .filter(|(_, expiry)| *expiry > now)
+            .collect();
+        log::info!("Sink: {} reconciling {} blocks", self.name, due.len());
+        for (element, expiry) in due {
+            match element.parse::<IpNetwork>() {
+                Ok(network) => {
+                    if let Err(e) = self.block(network, expiry - now).await {
+                        log::error!("Sink: {} unable to restore block: {}, {}", self.name, element, e);
+                    }
+                },
+                Err(e) => log::error!("Sink: {} skipping unreadable block: {}, {}", self.name, element, e),
+            }
+        }
+        self.persist();
+    }
+
+    async fn send(&mut self, alert: &Alert) -> Result<(), String> {
+        match key_address(&alert.key) {
+            _ if alert.kind == AlertKind::Novel => Ok(()), // informational, nothing to block
+            Some(network) if alert.kind == AlertKind::Release => self.release(network).await,
+            Some(network) => self.block(network, alert.block_seconds as u64).await,
+            None => {
+                log::debug!("Sink: {} skipping key without an address: {}", self.name, alert.key);
+                Ok(())
+            },
+        }
+    }
+}
+
+async fn execute(command: &FirewallCommand) -> Result<(), String> {
+    let mut child = Command::new(&command.program)
+        .args(&command.args)
+        .stdin(if command.stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("unable to run {}: {}", command.program, e))?;
+    if let (Some(script), Some(mut stdin)) = (&command.stdin, child.stdin.take()) {
+        stdin.write_all(script.as_bytes()).await.map_err(|e| e.to_string())?;
+    }
+    let output = child.wait_with_output().await.map_err(|e| e.to_string())?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(format!("{} exited with {}: {}", command.program, output.status, String::from_utf8_lossy(&output.stderr).trim()))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    fn settings(backend: Backend) -> FirewallSettings {
+        FirewallSettings {
+            backend,
+            set: "susspekt4".to_string(),
+            set6: Some("susspekt6".to_string()),
+            family: "inet".to_string(),
+            table: "filter".to_string(),
+            command: None,
+            state: None,
+        }
+    }
+
+    fn retry() -> RetryPolicy {
+        RetryPolicy { retries: 0, backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) }
+    }
+
+    fn alert(key: &str) -> Alert {
+        Alert { source: "192.168.0.7".parse().unwrap(), block_seconds: 600, ..Alert::test(key, SystemTime::now()) }
+    }
+
+    #[test]
+    fn test_commands() {
+        let sink = FirewallSink::new("fw".to_string(), settings(Backend::Ipset), true, retry());
+        assert_eq!(sink.block_command("susspekt4", "192.168.0.7", 600).to_string(), "ipset add susspekt4 192.168.0.7 timeout 600 -exist");
+        assert_eq!(sink.ensure_commands()[1].to_string(), "ipset create susspekt6 hash:net family inet6 timeout 0 -exist");
+
+        let sink = FirewallSink::new("fw".to_string(), settings(Backend::Nftables), true, retry());
+        let command = sink.block_command("susspekt4", "192.168.0.7", 600);
+        assert_eq!(command.args, vec!["-f", "-"]);
+        assert!(command.stdin.unwrap().ends_with("add element inet filter susspekt4 { 192.168.0.7 timeout 600s }\n"));
+    }
+
+    #[tokio::test]
+    async fn test_block_and_reconcile() {
+        let dir = std::env::temp_dir().join(format!("susspekt-firewall-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("commands.log");
+        let state = dir.join("state.json");
+
+        // a stand in for ipset, logging its arguments
+        let command = dir.join("ipset");
+        std::fs::write(&command, format!("#!/bin/sh\necho \"$@\" >> {}\n", log.display())).unwrap();
+        std::fs::set_permissions(&command, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut settings = settings(Backend::Ipset);
+        settings.command = Some(command.display().to_string());
+        settings.state = Some(state.display().to_string());
+
+        let mut sink = FirewallSink::new("fw".to_string(), settings.clone(), false, retry());
+        sink.start().await;
+        sink.send(&alert("579ccef312d18482fc42e2b822ca2430-192.168.0.7")).await.unwrap();
+        sink.send(&alert("579ccef312d18482fc42e2b822ca2430")).await.unwrap();
+        sink.send(&alert("579ccef312d18482fc42e2b822ca2430-192.168.0.8")).await.unwrap();
+        sink.send(&Alert { kind: AlertKind::Release, ..alert("579ccef312d18482fc42e2b822ca2430-192.168.0.8") }).await.unwrap();
+
+        let commands = std::fs::read_to_string(&log).unwrap();
+        assert_eq!(commands.lines().collect::<Vec<_>>(), vec![
+            "create susspekt4 hash:net family inet timeout 0 -exist",
+            "create susspekt6 hash:net family inet6 timeout 0 -exist",
+            "add susspekt4 192.168.0.7 timeout 600 -exist",
+            "add susspekt4 192.168.0.8 timeout 600 -exist",
+            "del susspekt4 192.168.0.8 -exist",
+        ]);
+
+        // a restart re-applies the block with what is left of its timeout
+        std::fs::remove_file(&log).unwrap();
+        let mut sink = FirewallSink::new("fw".to_string(), settings, false, retry());
+        sink.start().await;
+        let commands = std::fs::read_to_string(&log).unwrap();
+        let restored: Vec<&str> = commands.lines().skip(2).collect();
+        assert_eq!(restored.len(), 1, "{}", commands);
+        assert!(restored[0].starts_with("add susspekt4 192.168.0.7 timeout 6"), "{}", restored[0]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
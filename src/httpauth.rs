@@ -0,0 +1,263 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use reqwest::RequestBuilder;
+use serde::Deserialize;
+use sha2::Sha256;
+
+/**
+ * Credentials of the http sinks. Secrets are never taken on the command line, they are given as
+ * env:NAME or file:PATH and read at startup, e.g. --alert-bearer-token env:SUSSPEKT_TOKEN
+ *
+ * With an hmac secret every request carries a signature of its exact body and the time it was sent:
+ *
+ *   X-Susspekt-Signature: t=1701076465,v1=<hex HMAC-SHA256 of "1701076465.<body>">
+ *
+ * The receiver recomputes it with the shared secret, and should reject old timestamps to stop replays.
+ */
+
+pub const SIGNATURE_HEADER: &str = "X-Susspekt-Signature";
+
+const CERT_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const CERT_END: &str = "-----END CERTIFICATE-----";
+
+// where a secret is read from
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Secret {
+    Env(String),
+    File(String),
+}
+
+impl Secret {
+    pub fn parse(value: &str) -> Result<Secret, String> {
+        match value.split_once(':') {
+            Some(("env", name)) if !name.is_empty() => Ok(Secret::Env(name.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(Secret::File(path.to_string())),
+            _ => Err(format!("invalid secret '{}', expected env:NAME or file:PATH", value)),
+        }
+    }
+
+    // the secret, a trailing newline of a file is dropped
+    pub fn load(&self) -> Result<String, String> {
+        let secret = match self {
+            Secret::Env(name) => std::env::var(name)
+                .map_err(|e| format!("unable to read secret from env var {}: {}", name, e))?,
+            Secret::File(path) => std::fs::read_to_string(path)
+                .map_err(|e| format!("unable to read secret from '{}': {}", path, e))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+        };
+        if secret.is_empty() {
+            return Err(format!("secret {:?} is empty", self));
+        }
+        Ok(secret)
+    }
+}
+
+impl TryFrom<String> for Secret {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Secret::parse(&value)
+    }
+}
+
+// The credential and TLS options of an http sink, as configured
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HttpAuthConfig {
+    pub bearer_token: Option<Secret>,
+    pub basic_auth: Option<Secret>, // user:password
+    pub hmac_secret: Option<Secret>,
+    pub client_cert: Option<String>, // PEM file, with the chain if any
+    pub client_key: Option<String>, // PKCS#8 PEM file
+    pub ca_cert: Option<String>, // PEM bundle trusted on top of the system roots
+}
+
+// The loaded secrets
+#[derive(Debug, Clone, Default)]
+pub struct HttpAuth {
+    bearer_token: Option<String>,
+    basic_auth: Option<(String, String)>,
+    hmac_secret: Option<Vec<u8>>,
+}
+
+impl HttpAuthConfig {
+    // read the secrets, failing on a missing one or a conflicting combination
+    pub fn load(&self) -> Result<HttpAuth, String> {
+        if self.bearer_token.is_some() && self.basic_auth.is_some() {
+            return Err("only one of bearer_token and basic_auth can be set".to_string());
+        }
+        let basic_auth = match &self.basic_auth {
+            Some(secret) => {
+                let credentials = secret.load()?;
+                let (user, password) = credentials.split_once(':')
+                    .ok_or_else(|| format!("basic_auth {:?} must be user:password", secret))?;
+                Some((user.to_string(), password.to_string()))
+            },
+            None => None,
+        };
+        Ok(HttpAuth {
+            bearer_token: self.bearer_token.as_ref().map(Secret::load).transpose()?,
+            basic_auth,
+            hmac_secret: self.hmac_secret.as_ref().map(Secret::load).transpose()?.map(String::into_bytes),
+        })
+    }
+
+    // a client presenting the client certificate and trusting the CA bundle
+    pub fn client(&self, timeout: Duration) -> Result<reqwest::Client, String> {
+        let mut builder = reqwest::Client::builder().timeout(timeout);
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = reqwest::Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)
+                    .map_err(|e| format!("invalid client certificate '{}' or key '{}': {}", cert, key, e))?;
+                builder = builder.identity(identity);
+            },
+            (None, None) => {},
+            _ => return Err("client_cert and client_key must be set together".to_string()),
+        }
+        if let Some(path) = &self.ca_cert {
+            let bundle = String::from_utf8_lossy(&read(path)?).to_string();
+            let mut found = false;
+            for pem in pem_certificates(&bundle) {
+                let certificate = reqwest::Certificate::from_pem(pem.as_bytes())
+                    .map_err(|e| format!("invalid certificate in ca_cert '{}': {}", path, e))?;
+                builder = builder.add_root_certificate(certificate);
+                found = true;
+            }
+            if !found {
+                return Err(format!("no PEM certificates in ca_cert '{}'", path));
+            }
+        }
+        builder.build().map_err(|e| format!("unable to build the http client: {}", e))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.load()?;
+        self.client(Duration::from_secs(1))?;
+        Ok(())
+    }
+}
+
+impl HttpAuth {
+    // add the credentials and the signature of the body to a request
+    pub fn apply(&self, mut request: RequestBuilder, body: &str, ts: SystemTime) -> RequestBuilder {
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        if let Some((user, password)) = &self.basic_auth {
+            request = request.basic_auth(user, Some(password));
+        }
+        if let Some(secret) = &self.hmac_secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, ts, body));
+        }
+        request
+    }
+}
+
+// the signature header value of a body sent at ts
+pub fn signature(secret: &[u8], ts: SystemTime, body: &str) -> String {
+    let t = ts.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any length");
+    mac.update(format!("{}.{}", t, body).as_bytes());
+    format!("t={},v1={}", t, hex::encode(mac.finalize().into_bytes()))
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("unable to read '{}': {}", path, e))
+}
+
+// the certificates of a PEM bundle, reqwest only parses one at a time
+fn pem_certificates(bundle: &str) -> Vec<&str> {
+    let mut certificates = Vec::new();
+    let mut rest = bundle;
+    while let Some(start) = rest.find(CERT_BEGIN) {
+        match rest[start..].find(CERT_END) {
+            Some(end) => {
+                certificates.push(&rest[start..start + end + CERT_END.len()]);
+                rest = &rest[start + end + CERT_END.len()..];
+            },
+            None => break,
+        }
+    }
+    certificates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("susspekt-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_secret() {
+        assert_eq!(Secret::parse("env:SUSSPEKT_TOKEN").unwrap(), Secret::Env("SUSSPEKT_TOKEN".to_string()));
+        assert_eq!(Secret::parse("file:/etc/susspekt/token").unwrap(), Secret::File("/etc/susspekt/token".to_string()));
+        assert!(Secret::parse("hunter2").is_err());
+        assert!(Secret::parse("env:").is_err());
+
+        let path = temp_file("secret", "hunter2\n");
+        assert_eq!(Secret::File(path.clone()).load().unwrap(), "hunter2");
+        std::fs::remove_file(&path).unwrap();
+        assert!(Secret::File(path).load().is_err());
+        assert!(Secret::Env("SUSSPEKT_TEST_UNSET_SECRET".to_string()).load().unwrap_err().contains("SUSSPEKT_TEST_UNSET_SECRET"));
+    }
+
+    #[test]
+    fn test_load() {
+        let path = temp_file("basic", "susspekt:hunter2\n");
+        let config = HttpAuthConfig { basic_auth: Some(Secret::File(path.clone())), ..Default::default() };
+        assert_eq!(config.load().unwrap().basic_auth, Some(("susspekt".to_string(), "hunter2".to_string())));
+
+        let config = HttpAuthConfig { bearer_token: Some(Secret::File(path.clone())), ..config };
+        assert!(config.load().unwrap_err().contains("only one of"));
+
+        std::fs::write(&path, "hunter2").unwrap();
+        let config = HttpAuthConfig { basic_auth: Some(Secret::File(path.clone())), ..Default::default() };
+        assert!(config.load().unwrap_err().contains("user:password"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_signature() {
+        let ts = UNIX_EPOCH + Duration::from_millis(1701076465288);
+        // echo -n '1701076465.{"key":"a"}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(signature(b"secret", ts, r#"{"key":"a"}"#),
+            "t=1701076465,v1=8a657802396109606d6c8c19c1948d3f9ad9e89a36e6024f305ab6857fa63ffc");
+    }
+
+    #[test]
+    fn test_client() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = temp_file("client.pem", &cert.serialize_pem().unwrap());
+        let key_path = temp_file("client.key", &cert.serialize_private_key_pem());
+        let ca_path = temp_file("ca.pem", &format!("{}{}", cert.serialize_pem().unwrap(), cert.serialize_pem().unwrap()));
+        assert_eq!(pem_certificates(&std::fs::read_to_string(&ca_path).unwrap()).len(), 2);
+
+        let config = HttpAuthConfig {
+            client_cert: Some(cert_path.clone()),
+            client_key: Some(key_path.clone()),
+            ca_cert: Some(ca_path.clone()),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        let config = HttpAuthConfig { client_key: None, ..config };
+        assert!(config.validate().unwrap_err().contains("must be set together"));
+
+        let config = HttpAuthConfig { ca_cert: Some(key_path.clone()), ..Default::default() };
+        assert!(config.validate().unwrap_err().contains("no PEM certificates"));
+
+        for path in [cert_path, key_path, ca_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use crate::alert::{Alert, AlertKind, Metric};
+use crate::bucket::Bucket;
+use crate::keytemplate::KeyTemplate;
+use crate::overrides::Limits;
+
+/**
+ * A keyspace is a named set of buckets, aggregated on its own key template with its own threshold,
+ * window and block time. e.g: one keyspace on {ja3} to catch a ja3 from many addresses, and one on
+ * {src} to catch a single address hammering us.
+ *
+ * The TLS handshakes and the TCP control packets, SYN, FIN and RST without a ja3, of a key are counted
+ * in separate buckets against separate thresholds, so a NAT gateway closing lots of connections doesn't
+ * look like a ja3 flood. {ja3} renders as None for the control packets.
+ */
+
+pub const DEFAULT_KEYSPACE: &str = "default";
+
+// A keyspace as given on the command line, e.g: per_ip={ja3}-{src},threshold=100,window=10,block_seconds=600
+// the window is in seconds, or milliseconds with window_ms=. unset values fall back to --threshold,
+// --control-threshold, --window and --block-seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyspaceSpec {
+    pub name: String,
+    pub template: KeyTemplate,
+    pub threshold: Option<u64>,
+    pub control_threshold: Option<u64>,
+    pub window: Option<Duration>,
+    pub block_seconds: Option<u32>,
+}
+
+impl KeyspaceSpec {
+    pub fn parse(spec: &str) -> Result<KeyspaceSpec, String> {
+        let mut options = spec.split(',');
+        let (name, template) = options.next()
+            .and_then(|s| s.split_once('='))
+            .ok_or_else(|| format!("keyspace '{}' should start with name={{template}}", spec))?;
+
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("keyspace '{}' has an empty name", spec));
+        }
+
+        let mut keyspace = KeyspaceSpec {
+            name: name.to_string(),
+            template: KeyTemplate::parse(template.trim())?,
+            threshold: None,
+            control_threshold: None,
+            window: None,
+            block_seconds: None,
+        };
+
+        for option in options {
+            let (k, v) = option.split_once('=')
+                .ok_or_else(|| format!("keyspace option '{}' should be key=value in '{}'", option, spec))?;
+            let v = v.trim();
+            match k.trim() {
+                "threshold" => keyspace.threshold = Some(v.parse().map_err(|e| format!("invalid threshold '{}' in keyspace '{}': {}", v, spec, e))?),
+                "control_threshold" => keyspace.control_threshold = Some(v.parse().map_err(|e| format!("invalid control_threshold '{}' in keyspace '{}': {}", v, spec, e))?),
+                "window" => keyspace.window = Some(Duration::from_secs(v.parse().map_err(|e| format!("invalid window '{}' in keyspace '{}': {}", v, spec, e))?)),
+                "window_ms" => keyspace.window = Some(Duration::from_millis(v.parse().map_err(|e| format!("invalid window_ms '{}' in keyspace '{}': {}", v, spec, e))?)),
+                "block_seconds" => keyspace.block_seconds = Some(v.parse().map_err(|e| format!("invalid block_seconds '{}' in keyspace '{}': {}", v, spec, e))?),
+                other => return Err(format!("unknown keyspace option '{}' in '{}', expected threshold, control_threshold, window, window_ms or block_seconds", other, spec)),
+            }
+        }
+
+        if keyspace.window == Some(Duration::ZERO) {
+            return Err(format!("window must be greater than zero in keyspace '{}'", spec));
+        }
+
+        Ok(keyspace)
+    }
+}
+
+pub(crate) struct Keyspace {
+    pub name: String,
+    pub template: KeyTemplate,
+    pub threshold: u64,
+    pub control_threshold: u64, // for the TCP control packets of a key
+    pub window: Duration,
+    pub block_seconds: u32,
+    pub slot: Duration, // the resolution of the bucket rolling windows
+    pub buckets: HashMap<String, Bucket>, // HashMap to store Buckets against unique keys (like JA3 hashes).
+    pub control_buckets: HashMap<String, Bucket>, // the TCP control packets, by the same keys
+    pub blocked: HashMap<(Metric, String), Alert>, // the last alert of keys awaiting their release, with --release-cooldown
+    last_cleanup: Option<SystemTime>, // Capture time the buckets were last cleaned up, None until the first packet.
+    last_release_check: Option<SystemTime>, // Capture time the blocked keys were last checked for release.
+}
+
+impl Keyspace {
+    pub fn new(name: String, template: KeyTemplate, threshold: u64, control_threshold: u64, window: Duration, block_seconds: u32, slot: Duration) -> Self {
+        Keyspace {
+            name,
+            template,
+            threshold,
+            control_threshold,
+            window,
+            block_seconds,
+            slot,
+            buckets: HashMap::new(),
+            control_buckets: HashMap::new(),
+            blocked: HashMap::new(),
+            last_cleanup: None, // Packet timestamps may be in the past when replaying files, so start from the first packet.
+            last_release_check: None,
+        }
+    }
+
+    // the limits of the keyspace for a metric, before any per ja3 override
+    pub fn limits(&self, metric: Metric) -> Limits {
+        Limits {
+            threshold: match metric {
+                Metric::Handshake => self.threshold,
+                Metric::Control => self.control_threshold,
+            },
+            window: self.window,
+            block_seconds: self.block_seconds,
+        }
+    }
+
+    pub fn buckets(&self, metric: Metric) -> &HashMap<String, Bucket> {
+        match metric {
+            Metric::Handshake => &self.buckets,
+            Metric::Control => &self.control_buckets,
+        }
+    }
+
+    pub fn buckets_mut(&mut self, metric: Metric) -> &mut HashMap<String, Bucket> {
+        match metric {
+            Metric::Handshake => &mut self.buckets,
+            Metric::Control => &mut self.control_buckets,
+        }
+    }
+
+    // take the settings of a reloaded keyspace, keeping the buckets unless the key template changed.
+    // buckets are resized to a changed window or slot on their next update. blocked keys whose
+    // buckets are discarded are released on the next check.
+    pub fn reconfigure(&mut self, other: Keyspace) {
+        if self.template != other.template {
+            log::warn!("Key template of keyspace: {} changed from: {} to: {}, discarding {} buckets",
+                self.name, self.template, other.template, self.buckets.len() + self.control_buckets.len());
+            self.buckets.clear();
+            self.control_buckets.clear();
+            self.template = other.template;
+        }
+        self.threshold = other.threshold;
+        self.control_threshold = other.control_threshold;
+        self.window = other.window;
+        self.block_seconds = other.block_seconds;
+        self.slot = other.slot;
+    }
+
+    // increment the bucket for the key, and return if it violates the limits and should alert
+    pub fn update_or_insert_bucket(&mut self, metric: Metric, key: &str, limits: Limits, current_ts: SystemTime, log_create_buckets: bool) -> bool {
+        let (window, slot) = (limits.window, self.slot);
+        let bucket = self.buckets_mut(metric)
+            .entry(key.to_string())
+            .or_insert_with(|| {
+                let new_bucket = Bucket::new(key.to_string(), current_ts, window, slot);
+                if log_create_buckets {
+                    log::info!("Registering a new composite-key: {} as a bucket", key);
+                }
+                new_bucket
+            });
+
+        // the window or slot may have changed since the bucket was created, by a reload
+        if bucket.window != window || bucket.slot != slot {
+            bucket.resize(window, slot);
+        }
+
+        // increment the bucket for the timestamp ( which is now )
+        log::debug!("Troubleshooting window for key: {}", key);
+        bucket.update(current_ts);
+
+        // check if we tripped the threshold, suppressing re-alerts within the window. the capture
+        // timestamp is used rather than the wall clock, so files replay the same as live traffic.
+        let over = bucket.check_threshold(limits.threshold);
+        bucket.track_threshold(over, current_ts);
+        let should_alert = over && bucket.should_realert(current_ts, window);
+
+        if should_alert {
+            bucket.last_alert_ts = Some(current_ts);
+        }
+
+        should_alert
+    }
+
+    // record an alert, for its key to be released once it has calmed down
+    pub fn block(&mut self, alert: &Alert) {
+        self.blocked.insert((alert.metric, alert.key.clone()), alert.clone());
+    }
+
+    // the releases of blocked keys which have stayed under their threshold for the cooldown, checked
+    // once a slot. a released key alerts again as soon as it trips, rather than after the window
+    pub fn releases(&mut self, current_ts: SystemTime, cooldown: Duration) -> Vec<Alert> {
+        if self.blocked.is_empty() {
+            return Vec::new();
+        }
+        let last_check = *self.last_release_check.get_or_insert(current_ts);
+        if current_ts.duration_since(last_check).map_or(true, |elapsed| elapsed < self.slot) {
+            return Vec::new();
+        }
+        self.last_release_check = Some(current_ts);
+
+        let calm: Vec<(Metric, String)> = self.blocked.keys()
+            .filter(|(metric, key)| match self.buckets(*metric).get(key).and_then(|bucket| bucket.calm_since()) {
+                Some(calm_since) => current_ts.duration_since(calm_since).is_ok_and(|calm| calm >= cooldown),
+                None => true, // the bucket was discarded
+            })
+            .cloned()
+            .collect();
+
+        calm.into_iter()
+            .filter_map(|(metric, key)| {
+                let blocked = self.blocked.remove(&(metric, key.clone()))?;
+                let bucket = self.buckets_mut(metric).get_mut(&key);
+                let count = bucket.as_ref().map_or(0, |bucket| bucket.rolling_window.sum());
+                let evidence = bucket.as_ref().map_or_else(|| blocked.evidence.clone(), |bucket| bucket.evidence());
+                if let Some(bucket) = bucket {
+                    bucket.last_alert_ts = None;
+                }
+                log::info!("Releasing key: {}, in keyspace: {}, {} count: {}, under threshold: {} for {:?}", key, self.name, metric.as_str(), count, blocked.threshold, cooldown);
+                Some(Alert { kind: AlertKind::Release, count, ts: current_ts, evidence, ..blocked })
+            })
+            .collect()
+    }
+
+    // cleanup buckets that are quiet every 2x window time, returns true if a cleanup was done
+    pub fn periodic_cleanup(&mut self, current_ts: SystemTime) -> bool {
+
+        let last_cleanup = *self.last_cleanup.get_or_insert(current_ts);
+
+        if let Ok(duration_since_last_cleanup) = current_ts.duration_since(last_cleanup) {
+
+            if duration_since_last_cleanup >= self.window * 2 {
+                let bucket_count_before = self.buckets.len() + self.control_buckets.len();
+                self.cleanup_old_buckets(current_ts);
+                self.last_cleanup = Some(current_ts);
+                log::info!("Discarded idle buckets in keyspace: {}, count before: {}, count after: {}", self.name, bucket_count_before, self.buckets.len() + self.control_buckets.len());
+                return true
+            }
+
+        }
+
+        false
+    }
+
+    // Cleanup function to remove old buckets, idle for 2x their window, which may be overridden.
+    // buckets of blocked keys are kept until the key is released
+    pub fn cleanup_old_buckets(&mut self, current_ts: SystemTime) {
+        let blocked = &self.blocked;
+        for (metric, buckets) in [(Metric::Handshake, &mut self.buckets), (Metric::Control, &mut self.control_buckets)] {
+            buckets.retain(|key, bucket| {
+                if blocked.contains_key(&(metric, key.clone())) {
+                    return true;
+                }
+                // Check if the bucket's last timestamp is within the expiration duration
+                if let Ok(elapsed) = current_ts.duration_since(bucket.last_ts) {
+                    elapsed <= bucket.window * 2
+                } else {
+                    // In case current_ts is before bucket.last_ts, retain this bucket
+                    // This scenario is unlikely but should be handled
+                    true
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let spec = KeyspaceSpec::parse("per_ip={ja3}-{src},threshold=100,window=10,block_seconds=600").unwrap();
+        assert_eq!(spec.name, "per_ip");
+        assert_eq!(spec.template.to_string(), "{ja3}-{src}");
+        assert_eq!(spec.threshold, Some(100));
+        assert_eq!(spec.window, Some(Duration::from_secs(10)));
+        assert_eq!(spec.block_seconds, Some(600));
+
+        let spec = KeyspaceSpec::parse("burst={src},window_ms=200").unwrap();
+        assert_eq!(spec.window, Some(Duration::from_millis(200)));
+
+        let spec = KeyspaceSpec::parse("ja3={ja3}").unwrap();
+        assert_eq!(spec.threshold, None);
+        assert_eq!(spec.window, None);
+        assert_eq!(spec.block_seconds, None);
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let ts = SystemTime::now();
+        let second = Duration::from_secs(1);
+        let mut keyspace = Keyspace::new("ja3".to_string(), KeyTemplate::parse("{ja3}").unwrap(), 100, 100, Duration::from_secs(60), 86400, second);
+        keyspace.update_or_insert_bucket(Metric::Handshake, "somekey", keyspace.limits(Metric::Handshake), ts, false);
+
+        keyspace.reconfigure(Keyspace::new("ja3".to_string(), KeyTemplate::parse("{ja3}").unwrap(), 5, 5, Duration::from_secs(30), 600, second));
+        assert_eq!(keyspace.threshold, 5);
+        assert_eq!(keyspace.window, Duration::from_secs(30));
+        assert_eq!(keyspace.block_seconds, 600);
+        keyspace.update_or_insert_bucket(Metric::Handshake, "somekey", keyspace.limits(Metric::Handshake), ts, false);
+        assert_eq!(keyspace.buckets.get("somekey").unwrap().window, Duration::from_secs(30));
+        assert_eq!(keyspace.buckets.get("somekey").unwrap().rolling_window.sum(), 2);
+
+        keyspace.reconfigure(Keyspace::new("ja3".to_string(), KeyTemplate::parse("{ja3}-{src}").unwrap(), 5, 5, Duration::from_secs(30), 600, second));
+        assert!(keyspace.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_releases() {
+        let ts = SystemTime::now();
+        let second = Duration::from_secs(1);
+        let cooldown = Duration::from_secs(10);
+        let mut keyspace = Keyspace::new("ja3".to_string(), KeyTemplate::parse("{ja3}").unwrap(), 3, 3, Duration::from_secs(10), 600, second);
+
+        let alerted: Vec<bool> = (0..5).map(|_| keyspace.update_or_insert_bucket(Metric::Handshake, "somekey", keyspace.limits(Metric::Handshake), ts, false)).collect();
+        assert_eq!(alerted, vec![false, false, false, true, false]);
+        keyspace.block(&Alert {
+            keyspace: "ja3".to_string(),
+            count: 4,
+            threshold: 3,
+            window: Duration::from_secs(10),
+            block_seconds: 600,
+            ..Alert::test("somekey", ts)
+        });
+
+        // quiet, the counts leave the window after 10s and the cooldown runs 10s from there
+        assert!(keyspace.releases(ts + second, cooldown).is_empty());
+        assert!(keyspace.releases(ts + Duration::from_secs(15), cooldown).is_empty());
+        keyspace.cleanup_old_buckets(ts + Duration::from_secs(21));
+        let releases = keyspace.releases(ts + Duration::from_secs(21), cooldown);
+        assert_eq!(releases.len(), 1);
+        assert_eq!(releases[0].kind, AlertKind::Release);
+        assert_eq!(releases[0].key, "somekey");
+        assert_eq!(releases[0].block_seconds, 600);
+        assert!(keyspace.blocked.is_empty());
+
+        // a released key alerts again straight away
+        let alerted: Vec<bool> = (0..4).map(|_| keyspace.update_or_insert_bucket(Metric::Handshake, "somekey", keyspace.limits(Metric::Handshake), ts + Duration::from_secs(22), false)).collect();
+        assert_eq!(alerted, vec![false, false, false, true]);
+    }
+
+    #[test]
+    fn test_control_metric() {
+        let ts = SystemTime::now();
+        let second = Duration::from_secs(1);
+        let mut keyspace = Keyspace::new("src".to_string(), KeyTemplate::parse("{src}").unwrap(), 3, 10, Duration::from_secs(10), 600, second);
+        assert_eq!(keyspace.limits(Metric::Control).threshold, 10);
+
+        // the control packets of a key neither add to its handshakes nor share their threshold
+        let alerted: Vec<bool> = (0..5).map(|_| keyspace.update_or_insert_bucket(Metric::Control, "8.8.8.8", keyspace.limits(Metric::Control), ts, false)).collect();
+        assert_eq!(alerted, vec![false; 5]);
+        assert!(!keyspace.buckets.contains_key("8.8.8.8"));
+        let alerted: Vec<bool> = (0..4).map(|_| keyspace.update_or_insert_bucket(Metric::Handshake, "8.8.8.8", keyspace.limits(Metric::Handshake), ts, false)).collect();
+        assert_eq!(alerted, vec![false, false, false, true]);
+        assert_eq!(keyspace.control_buckets.get("8.8.8.8").unwrap().rolling_window.sum(), 5);
+
+        assert_eq!(KeyspaceSpec::parse("nat={src},control_threshold=50").unwrap().control_threshold, Some(50));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(KeyspaceSpec::parse("{ja3}").is_err());
+        assert!(KeyspaceSpec::parse("={ja3}").is_err());
+        assert!(KeyspaceSpec::parse("ja3={foo}").is_err());
+        assert!(KeyspaceSpec::parse("ja3={ja3},threshold=lots").is_err());
+        assert!(KeyspaceSpec::parse("ja3={ja3},window=0").is_err());
+        assert!(KeyspaceSpec::parse("ja3={ja3},window_ms=0").is_err());
+        assert!(KeyspaceSpec::parse("ja3={ja3},colour=red").is_err());
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+use std::net::IpAddr;
+use ipnetwork::IpNetwork;
+
+use crate::address;
+use crate::monitor::PacketEvent;
+
+/**
+ * Aggregation key templates, e.g: `{ja3}-{src_net/24}-{dst}:{dst_port}`
+ *
+ * A template is compiled once at startup and rendered for every packet. Supported fields:
+ *
+ *   {ja3}                  the md5 ja3 hash
+ *   {src}, {dst}           the source / destination address
+ *   {src_net/N}            the source network, IPv4 masked to /N. IPv6 uses --agg-ipv6-prefix
+ *   {src_net/N/M}          the source network, IPv4 masked to /N, IPv6 masked to /M
+ *   {dst_net/N[/M]}        the same for the destination
+ *   {src_port}, {dst_port} the source / destination port
+ *   {sni}                  the TLS server name, "None" if the ClientHello had none
+ */
+
+pub const JA3_TEMPLATE: &str = "{ja3}";
+pub const JA3_SRC_TEMPLATE: &str = "{ja3}-{src}";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Ja3,
+    Src,
+    Dst,
+    SrcNet(u8, Option<u8>),
+    DstNet(u8, Option<u8>),
+    SrcPort,
+    DstPort,
+    Sni,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl KeyTemplate {
+    // Compile a template, failing on unknown fields or malformed braces
+    pub fn parse(template: &str) -> Result<KeyTemplate, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' => {
+                    let mut field = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some('{') | None => return Err(format!("unterminated field '{{{}' in key template '{}'", field, template)),
+                            Some(c) => field.push(c),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Self::parse_field(field.trim()).map_err(|e| format!("{} in key template '{}'", e, template))?);
+                }
+                '}' => return Err(format!("unmatched '}}' in key template '{}'", template)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        if !parts.iter().any(|part| !matches!(part, Part::Literal(_))) {
+            return Err(format!("key template '{}' does not reference any field", template));
+        }
+
+        Ok(KeyTemplate { template: template.to_string(), parts })
+    }
+
+    fn parse_field(field: &str) -> Result<Part, String> {
+        match field {
+            "ja3" => Ok(Part::Ja3),
+            "src" => Ok(Part::Src),
+            "dst" => Ok(Part::Dst),
+            "src_port" => Ok(Part::SrcPort),
+            "dst_port" => Ok(Part::DstPort),
+            "sni" => Ok(Part::Sni),
+            _ => {
+                if let Some(prefixes) = field.strip_prefix("src_net/") {
+                    let (v4, v6) = Self::parse_prefixes(field, prefixes)?;
+                    Ok(Part::SrcNet(v4, v6))
+                } else if let Some(prefixes) = field.strip_prefix("dst_net/") {
+                    let (v4, v6) = Self::parse_prefixes(field, prefixes)?;
+                    Ok(Part::DstNet(v4, v6))
+                } else {
+                    Err(format!("unknown field '{{{}}}', expected one of {{ja3}}, {{src}}, {{dst}}, {{src_net/N}}, {{dst_net/N}}, {{src_port}}, {{dst_port}}, {{sni}}", field))
+                }
+            }
+        }
+    }
+
+    // parse the N or N/M of a network field
+    fn parse_prefixes(field: &str, prefixes: &str) -> Result<(u8, Option<u8>), String> {
+        let mut split = prefixes.split('/');
+        let v4 = split.next()
+            .and_then(|p| p.parse::<u8>().ok())
+            .filter(|p| *p <= 32)
+            .ok_or_else(|| format!("invalid IPv4 prefix in field '{{{}}}', expected 0-32", field))?;
+        let v6 = match split.next() {
+            Some(p) => Some(p.parse::<u8>().ok()
+                .filter(|p| *p <= 128)
+                .ok_or_else(|| format!("invalid IPv6 prefix in field '{{{}}}', expected 0-128", field))?),
+            None => None,
+        };
+        if split.next().is_some() {
+            return Err(format!("too many prefixes in field '{{{}}}'", field));
+        }
+        Ok((v4, v6))
+    }
+
+    // Render the key for a packet. Packets without a ja3, e.g. SYN/FIN/RST, render {ja3} as None, their
+    // counts are kept apart from the handshakes of the same key. A template without a source field keys
+    // them on their source as None-{src}, so they don't all add up in one key.
+    pub fn render(&self, event: &PacketEvent, ipv6_prefix: Option<u8>) -> String {
+        let ja3 = match &event.ja3 {
+            Some(ja3) => ja3.as_str(),
+            None if !self.parts.iter().any(|part| matches!(part, Part::Src | Part::SrcNet(..))) =>
+                return format!("None-{}", address::source_key(&event.source, ipv6_prefix)),
+            None => "None",
+        };
+
+        let mut key = String::with_capacity(64);
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(literal) => key.push_str(literal),
+                Part::Ja3 => key.push_str(ja3),
+                Part::Src => key.push_str(&address::source_key(&event.source, ipv6_prefix)),
+                Part::Dst => key.push_str(&address::normalize(&event.destination).to_string()),
+                Part::SrcNet(v4, v6) => key.push_str(&Self::network(&event.source, *v4, v6.or(ipv6_prefix))),
+                Part::DstNet(v4, v6) => key.push_str(&Self::network(&event.destination, *v4, v6.or(ipv6_prefix))),
+                Part::SrcPort => key.push_str(&event.source_port.to_string()),
+                Part::DstPort => key.push_str(&event.destination_port.to_string()),
+                Part::Sni => key.push_str(event.sni.as_deref().unwrap_or("None")),
+            }
+        }
+        key
+    }
+
+    // mask an address to its network, IPv6 is left as is when no prefix is configured for it
+    fn network(ip: &IpAddr, v4: u8, v6: Option<u8>) -> String {
+        match address::normalize(ip) {
+            IpAddr::V4(addr) => match IpNetwork::new(IpAddr::V4(addr), v4) {
+                Ok(network) => format!("{}/{}", network.network(), v4),
+                Err(_) => addr.to_string(),
+            },
+            addr => address::source_key(&addr, v6),
+        }
+    }
+}
+
+impl fmt::Display for KeyTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::TcpFlags;
+    use std::time::SystemTime;
+
+    fn event(ja3: Option<&str>, source: &str, destination: &str) -> PacketEvent {
+        PacketEvent {
+            ja3: ja3.map(|s| s.to_string()),
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+            source_port: 50123,
+            destination_port: 443,
+            sni: Some("example.com".to_string()),
+            flags: TcpFlags::default(),
+            ts: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(KeyTemplate::parse("{ja3}-{source}").unwrap_err().contains("unknown field '{source}'"));
+        assert!(KeyTemplate::parse("{ja3").is_err());
+        assert!(KeyTemplate::parse("ja3}").is_err());
+        assert!(KeyTemplate::parse("static").is_err());
+        assert!(KeyTemplate::parse("{src_net/33}").is_err());
+        assert!(KeyTemplate::parse("{src_net/24/129}").is_err());
+        assert!(KeyTemplate::parse("{src_net/24/64/1}").is_err());
+    }
+
+    #[test]
+    fn test_render() {
+        let e = event(Some("579ccef312d18482fc42e2b822ca2430"), "192.168.0.7", "34.149.100.209");
+
+        assert_eq!(KeyTemplate::parse(JA3_TEMPLATE).unwrap().render(&e, None), "579ccef312d18482fc42e2b822ca2430");
+        assert_eq!(KeyTemplate::parse(JA3_SRC_TEMPLATE).unwrap().render(&e, None), "579ccef312d18482fc42e2b822ca2430-192.168.0.7");
+        assert_eq!(KeyTemplate::parse("{ja3}-{src_net/24}-{dst}:{dst_port}").unwrap().render(&e, None),
+                   "579ccef312d18482fc42e2b822ca2430-192.168.0.0/24-34.149.100.209:443");
+        assert_eq!(KeyTemplate::parse("{ja3}-{sni}").unwrap().render(&e, None), "579ccef312d18482fc42e2b822ca2430-example.com");
+        assert_eq!(KeyTemplate::parse("{src}:{src_port}").unwrap().render(&e, None), "192.168.0.7:50123");
+    }
+
+    #[test]
+    fn test_render_ipv6() {
+        let e = event(Some("579ccef312d18482fc42e2b822ca2430"), "2001:db8:1:2:aaaa:bbbb:cccc:dddd", "2001:db8::1");
+
+        let template = KeyTemplate::parse("{ja3}-{src_net/24}").unwrap();
+        assert_eq!(template.render(&e, None), "579ccef312d18482fc42e2b822ca2430-2001:db8:1:2:aaaa:bbbb:cccc:dddd");
+        assert_eq!(template.render(&e, Some(64)), "579ccef312d18482fc42e2b822ca2430-2001:db8:1:2::/64");
+
+        let template = KeyTemplate::parse("{ja3}-{src_net/24/48}").unwrap();
+        assert_eq!(template.render(&e, Some(64)), "579ccef312d18482fc42e2b822ca2430-2001:db8:1::/48");
+    }
+
+    #[test]
+    fn test_render_without_ja3() {
+        let e = event(None, "192.168.0.7", "34.149.100.209");
+        assert_eq!(KeyTemplate::parse(JA3_TEMPLATE).unwrap().render(&e, None), "None-192.168.0.7");
+        assert_eq!(KeyTemplate::parse("{ja3}-{dst}").unwrap().render(&e, None), "None-192.168.0.7");
+        assert_eq!(KeyTemplate::parse(JA3_SRC_TEMPLATE).unwrap().render(&e, None), "None-192.168.0.7");
+        assert_eq!(KeyTemplate::parse("{ja3}-{src_net/24}").unwrap().render(&e, None), "None-192.168.0.0/24");
+        assert_eq!(KeyTemplate::parse("{src}").unwrap().render(&e, None), "192.168.0.7");
+    }
+}
@@ -2,10 +2,8 @@
 // Licensed under the MIT License (https://opensource.org/licenses/MIT)
 // This file may not be copied, modified, or distributed except according to those terms.
 
-use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 use clap::builder::Str;
-use clap::Parser;
 use args::AppArgs;
 mod whitelist;
 use md5::Digest;
@@ -13,19 +11,41 @@ use env_logger::Env;
 use ja3::{Ja3, Ja3Hash};
 extern crate env_logger;
 use log::info;
-use time::Instant;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::Sender;
 use tokio::task::JoinHandle;
+use crate::alert::Alert;
 use crate::logdata::LogData;
-use crate::monitor::Monitor;
-use crate::poster::HttpPoster;
+use crate::monitor::{Monitor, PacketEvent, TcpFlags};
+use crate::dispatcher::Dispatcher;
 use crate::whitelist::Whitelist;
 
 mod args;
 mod monitor;
 mod bucket;
+mod baseline;
+mod cardinality;
+mod connections;
+mod hyperloglog;
+mod novelty;
 mod rollingwindow;
 mod poster;
+mod httpauth;
+mod payloadtemplate;
 mod logdata;
+mod address;
+mod keytemplate;
+mod keyspace;
+mod alert;
+mod config;
+mod overrides;
+mod retry;
+mod outbox;
+mod sink;
+mod filesink;
+mod firewallsink;
+mod syslogsink;
+mod dispatcher;
 
 const BUFFER_SIZE: usize = 65536 * 1;
 
@@ -56,84 +76,108 @@ async fn main() {
 
     info!("{}", logo);
 
-    // argparse
-    let args = AppArgs::parse();
+    // argparse, merged with the --config file and validated
+    let args = AppArgs::load();
 
-    // whitelist, to be used for ignoring processing, or ignoring alerting.
-    let whitelist = Whitelist::new(
-        Arc::new(args.parse_whitelist_networks()),
-        Arc::new(args.parse_whitelist_ja3()),
-    );
+    // whitelist, to be used for ignoring processing, or ignoring alerting. validated by load above
+    let whitelist = args.whitelist().unwrap();
+
+    // the keyspaces to aggregate on, their key templates are compiled once and rendered for every packet
+    for keyspace in args.keyspaces() {
+        info!("Aggregating keyspace: {} on key template: {}, threshold: {}, window: {:?}, block seconds: {}",
+            keyspace.name, keyspace.template, keyspace.threshold, keyspace.window, keyspace.block_seconds);
+    }
 
     // setup the eventing system
-    let (monitor_tx, mut monitor_rx) = tokio::sync::mpsc::channel::<String>(BUFFER_SIZE);
-    let (alerter_tx, mut alerter_rx) = tokio::sync::mpsc::channel::<String>(BUFFER_SIZE);
+    // keys travel with the capture timestamp of the packet that produced them, so that replaying
+    // a pcap file produces the same windows as the live sniffer would have
+    let (monitor_tx, mut monitor_rx) = tokio::sync::mpsc::channel::<PacketEvent>(BUFFER_SIZE);
+    let (alerter_tx, mut alerter_rx) = tokio::sync::mpsc::channel::<Alert>(BUFFER_SIZE);
 
     // holder for tasks
     let mut tasks: Vec<JoinHandle<()>> = Vec::new();
 
-    // alerter event listener
-    let poster_args = args.clone();
+    // alerter event listener, fans the alerts out to the sinks
+    let mut dispatcher = Dispatcher::new();
+    for (sink, filter) in sink::build(&args) {
+        info!("Alerting to sink: {}, filter: {:?}", sink.name(), filter);
+        dispatcher.add(sink, filter);
+    }
     let poster_task = tokio::spawn(async move {
-        // alerter
-        let mut poster = HttpPoster::new(poster_args);
-
         // read keys from the Sender
-        while let Some(key) = alerter_rx.recv().await {
-            match poster.alert(key).await {
-                Ok(_) => {},
-                Err(e) => log::error!("Error posting: {}", e)
-            }
+        while let Some(alert) = alerter_rx.recv().await {
+            dispatcher.dispatch(alert).await;
         }
+        // let the sinks drain before exiting
+        dispatcher.close().await;
     });
     tasks.push(poster_task);
 
 
+    // reload the whitelist and thresholds on SIGHUP, the monitor swaps them in between packets
+    let (reload_tx, mut reload_rx) = tokio::sync::mpsc::channel::<(AppArgs, Whitelist)>(1);
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                log::error!("Unable to listen for SIGHUP, config reload disabled: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading config");
+            match AppArgs::reload().and_then(|args| args.whitelist().map(|whitelist| (args, whitelist))) {
+                Ok(reloaded) => {
+                    if reload_tx.send(reloaded).await.is_err() {
+                        break;
+                    }
+                },
+                Err(e) => log::error!("Config reload failed, keeping the running config: {}", e),
+            }
+        }
+    });
+
     // monitoring event listener, passes keys to the monitoring impl
     let monitor_args = args.clone();
-    tokio::spawn(async move {
-        let mut monitor = Monitor::new(monitor_args.clone() );
-        let mut timer = Instant::now();
-        // continuously read keys from the Sender
-        while let Some(key) = monitor_rx.recv().await {
-            log::debug!("process key: {:?}", key);
-            if monitor.process_key(&key, SystemTime::now()) {
-                if let Err(e) = alerter_tx.send(key).await {
-                    log::error!("Failed to send alert: {}", e);
+    let monitor_task = tokio::spawn(async move {
+        let mut monitor = Monitor::new(monitor_args.clone(), whitelist);
+        // continuously read packets from the Sender, and reloads from the SIGHUP handler
+        loop {
+            tokio::select! {
+                Some((args, whitelist)) = reload_rx.recv() => monitor.reload(args, whitelist),
+                event = monitor_rx.recv() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break,
+                    };
+                    log::debug!("process packet from: {:?}", event.source);
+                    if monitor.skip_whitelisted(&event.source) {
+                        continue;
+                    }
+                    for alert in monitor.process_event(&event) {
+                        if let Err(e) = alerter_tx.send(alert).await {
+                            log::error!("Failed to send alert: {}", e);
+                        }
+                    }
                 }
             }
         }
+        log::info!("Monitor finished, whitelisted packets skipped: {}", monitor.skipped());
     });
+    tasks.push(monitor_task);
 
 
     // file parser
     if args.file.is_some() {
         info!("Switching to file parsing mode");
-        let ja3 = Ja3::new(args.file.unwrap())
+        let ja3 = Ja3::new(args.file.clone().unwrap())
             .process_pcap()
             .unwrap();
         for packet in ja3 {
-
-            let ja3_str = generate_key(&packet, args.agg_ip);
-
-            let log_data = LogData {
-                source: packet.source.to_string(),
-                destination: packet.destination.to_string(),
-                ja3: ja3_str.clone(),
-                packet_size: packet.packet_size,
-                is_handshake: packet.is_handshake,
-                ethernet_frame_size: packet.ethernet_frame_size,
-                is_syn: packet.is_syn,
-                is_fin: packet.is_fin,
-                is_rst: packet.is_rst,
-            };
-
-            if packet.is_fin || packet.is_rst || packet.is_syn || packet.is_handshake{
-                let log_json = serde_json::to_string(&log_data).unwrap_or_else(|e| format!("Error serializing log data: {}", e));
-                info!("{}", log_json);
-                let _ = monitor_tx.send(ja3_str).await; // pass to the monitoring impl
-            }
+            forward_packet(&packet, &monitor_tx).await;
         }
+        // closing the channel lets the monitor, and in turn the alerter, drain and exit
+        drop(monitor_tx);
         log::info!("Waiting for threads to finish up...");
         for handle in tasks {
             match handle.await {
@@ -144,30 +188,11 @@ async fn main() {
             }
         }
     } else {
-        let mut ja3 = Ja3::new(args.interface.unwrap())
+        let mut ja3 = Ja3::new(args.interface.clone().unwrap())
             .process_live()
             .unwrap();
         while let Some(packet) = ja3.next() {
-
-            let ja3_str = generate_key(&packet, args.agg_ip);
-
-            let log_data = LogData {
-                source: packet.source.to_string(),
-                destination: packet.destination.to_string(),
-                ja3: ja3_str.clone(),
-                packet_size: packet.packet_size,
-                is_handshake: packet.is_handshake,
-                ethernet_frame_size: packet.ethernet_frame_size,
-                is_syn: packet.is_syn,
-                is_fin: packet.is_fin,
-                is_rst: packet.is_rst,
-            };
-
-            if packet.is_fin || packet.is_rst || packet.is_syn || packet.is_handshake{
-                let log_json = serde_json::to_string(&log_data).unwrap_or_else(|e| format!("Error serializing log data: {}", e));
-                info!("{}", log_json);
-                let _ = monitor_tx.send(ja3_str).await; // pass to the monitoring impl
-            }
+            forward_packet(&packet, &monitor_tx).await;
         }
     }
 
@@ -182,22 +207,36 @@ fn digest_to_string(digest: Digest) -> String {
     format!("{:x}", digest)
 }
 
-fn generate_key(packet: &Ja3Hash, agg_ip: bool) -> String {
-    let ja3_str = match packet.hash {
-        Some(hash) => {
-            if agg_ip {
-                format!("{:x}-{}", hash, packet.source)
-            } else {
-                format!("{:x}", hash)
-            }
-        }
-        None => {
-            if agg_ip {
-                format!("None-{}", packet.source)
-            } else {
-                format!("None-{}", packet.source)
-            }
-        }
+/// log the interesting packets and pass them, stamped with the capture time, to the monitoring impl
+async fn forward_packet(packet: &Ja3Hash, monitor_tx: &Sender<PacketEvent>) {
+    if !(packet.is_fin || packet.is_rst || packet.is_syn || packet.is_handshake) {
+        return;
+    }
+
+    let event = PacketEvent {
+        ja3: packet.hash.map(digest_to_string),
+        source: packet.source,
+        destination: packet.destination,
+        source_port: packet.source_port,
+        destination_port: packet.destination_port,
+        sni: packet.sni.clone(),
+        flags: TcpFlags { syn: packet.is_syn, fin: packet.is_fin, rst: packet.is_rst },
+        ts: packet.timestamp,
     };
-    return ja3_str;
+
+    let log_data = LogData {
+        source: packet.source.to_string(),
+        destination: packet.destination.to_string(),
+        ja3: event.ja3.clone().unwrap_or_else(|| "None".to_string()),
+        packet_size: packet.packet_size,
+        is_handshake: packet.is_handshake,
+        ethernet_frame_size: packet.ethernet_frame_size,
+        is_syn: packet.is_syn,
+        is_fin: packet.is_fin,
+        is_rst: packet.is_rst,
+    };
+
+    let log_json = serde_json::to_string(&log_data).unwrap_or_else(|e| format!("Error serializing log data: {}", e));
+    info!("{}", log_json);
+    let _ = monitor_tx.send(event).await; // pass to the monitoring impl
 }
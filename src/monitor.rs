@@ -3,156 +3,301 @@
 // This file may not be copied, modified, or distributed except according to those terms.
 
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::time::SystemTime;
 use time::{Duration, Instant};
 
+use crate::alert::{Alert, AlertKind, Metric};
 use crate::args::AppArgs;
-use crate::bucket::Bucket;
+use crate::baseline::BaselineDetector;
+use crate::cardinality::CardinalityDetector;
+use crate::connections::ConnectionTracker;
+use crate::keyspace::Keyspace;
+use crate::novelty::NoveltyDetector;
+use crate::overrides::{limits_for, Limits, Override};
+use crate::whitelist::Whitelist;
+
+// A packet of interest, as passed from the sniffer to the monitor
+pub(crate) struct PacketEvent {
+    pub ja3: Option<String>, // the md5 ja3 hash, None for packets which are not a ClientHello
+    pub source: IpAddr, // source address of the packet, checked against the whitelist
+    pub destination: IpAddr,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub sni: Option<String>, // server name from the ClientHello, if any
+    pub flags: TcpFlags,
+    pub ts: SystemTime, // capture timestamp of the packet
+}
+
+// The TCP control flags of a packet
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct TcpFlags {
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+}
+
+// a ja3 and a capture time for the tests, 2023-11-27T09:14:25Z
+#[cfg(test)]
+pub(crate) const TEST_JA3: &str = "579ccef312d18482fc42e2b822ca2430";
+
+#[cfg(test)]
+pub(crate) fn test_start() -> SystemTime {
+    std::time::UNIX_EPOCH + std::time::Duration::from_secs(1701076465)
+}
+
+#[cfg(test)]
+impl PacketEvent {
+    // a ClientHello of the TEST_JA3 from 8.8.8.8:50000 to 192.168.0.1:443, for the tests to override
+    // the fields they care about
+    pub fn test(ts: SystemTime) -> PacketEvent {
+        PacketEvent {
+            ja3: Some(TEST_JA3.to_string()),
+            source: "8.8.8.8".parse().unwrap(),
+            destination: "192.168.0.1".parse().unwrap(),
+            source_port: 50000,
+            destination_port: 443,
+            sni: None,
+            flags: TcpFlags::default(),
+            ts,
+        }
+    }
+}
 
 pub(crate) struct Monitor {
     args: AppArgs,
-    buckets: HashMap<String, Bucket>, // HashMap to store Buckets against unique keys (like JA3 hashes).
+    keyspaces: Vec<Keyspace>, // the keyspaces to aggregate on, each with their own buckets
+    overrides: Vec<Override>, // per ja3 or key prefix limits, consulted before the keyspace limits
+    baseline: Option<BaselineDetector>, // learned per ja3 rates, with --baseline-sigma
+    cardinality: Option<CardinalityDetector>, // distinct sources per ja3, with the --cardinality-* limits
+    novelty: Option<NoveltyDetector>, // the ja3s seen before, with --known-ja3-file
+    connections: Option<ConnectionTracker>, // connection state per source, with the --conn-* limits
     _last_check: SystemTime, // Last time the data was checked.
-    last_cleanup: SystemTime, // Last time the buckets were cleaned up.
     ja3_last_alerts: HashMap<String, SystemTime>, // Tracks the last alert time for each JA3 hash.
-    bucket_window: usize, // the window to bucket by, just a conversion of type for the window for speed
     counter: u64,
     last_counter_reset: Instant,
-    whitelisted_ja3: Vec<String>,
+    whitelist: Whitelist,
+    skipped: u64, // packets skipped because their source is whitelisted
 }
 
 impl Monitor {
-    pub fn new(args: AppArgs) -> Self {
-        let bucket_window = args.window as usize;
+    pub fn new(args: AppArgs, whitelist: Whitelist) -> Self {
+        let keyspaces = args.keyspaces();
         Monitor {
             args: args.clone(),
-            buckets: HashMap::new(), // Initialize buckets as an empty HashMap.
+            keyspaces,
+            overrides: args.overrides.clone(),
+            baseline: args.baseline().map(BaselineDetector::new),
+            cardinality: args.cardinality().map(CardinalityDetector::new),
+            novelty: args.novelty().map(NoveltyDetector::open),
+            connections: args.connections().map(ConnectionTracker::new),
             _last_check: SystemTime::now(), // Initialize last check to the current time.
-            last_cleanup: SystemTime::now(), // Initialize last cleanup to the current time.
             ja3_last_alerts: HashMap::new(), // Initialize ja3_last_alerts as an empty HashMap.
-            bucket_window: bucket_window, // bucket window is conversion
             counter: 0,
             last_counter_reset: Instant::now(),
-            whitelisted_ja3: args.clone().parse_whitelist_ja3(),
+            whitelist,
+            skipped: 0,
         }
     }
 
-    // process a key, and return if its in violation or not
-    pub fn process_key(&mut self, ja3: &str, current_ts: SystemTime) -> bool {
-
-        if self.whitelisted_ja3.contains(&ja3.split("-").next().unwrap_or("None").to_string()) {
-            log::debug!("{} is in whitelisted ja3", ja3);
-            return false
+    // swap in reloaded settings and whitelist. buckets of keyspaces which still exist are kept, so
+    // a reload does not cause a blind spot
+    pub fn reload(&mut self, args: AppArgs, whitelist: Whitelist) {
+        let mut previous: HashMap<String, Keyspace> = self.keyspaces.drain(..)
+            .map(|keyspace| (keyspace.name.clone(), keyspace))
+            .collect();
+
+        for keyspace in args.keyspaces() {
+            match previous.remove(&keyspace.name) {
+                Some(mut existing) => {
+                    existing.reconfigure(keyspace);
+                    self.keyspaces.push(existing);
+                }
+                None => {
+                    log::info!("Adding keyspace: {}", keyspace.name);
+                    self.keyspaces.push(keyspace);
+                }
+            }
+        }
+        for name in previous.keys() {
+            log::info!("Removing keyspace: {}", name);
         }
 
-        self.counter+=1;
-        // self.print_stats();
-
-        log::debug!("{} processing key: {}", self.counter, ja3);
+        self.baseline = match (self.baseline.take(), args.baseline()) {
+            (Some(mut baseline), Some(settings)) => {
+                baseline.reconfigure(settings);
+                Some(baseline)
+            },
+            (_, settings) => settings.map(BaselineDetector::new),
+        };
+        self.cardinality = match (self.cardinality.take(), args.cardinality()) {
+            (Some(mut cardinality), Some(settings)) => {
+                cardinality.reconfigure(settings);
+                Some(cardinality)
+            },
+            (_, settings) => settings.map(CardinalityDetector::new),
+        };
+        self.novelty = match (self.novelty.take(), args.novelty()) {
+            (Some(mut novelty), Some(settings)) if novelty.path() == settings.path => {
+                novelty.reconfigure(settings);
+                Some(novelty)
+            },
+            (_, settings) => settings.map(NoveltyDetector::open),
+        };
+        self.connections = match (self.connections.take(), args.connections()) {
+            (Some(mut connections), Some(settings)) => {
+                connections.reconfigure(settings);
+                Some(connections)
+            },
+            (_, settings) => settings.map(ConnectionTracker::new),
+        };
+        self.overrides = args.overrides.clone();
+        self.args = args;
+        self.whitelist = whitelist;
+        log::info!("Reloaded config, keyspaces: {}, overrides: {}, whitelisted networks: {}, whitelisted ja3s: {}",
+            self.keyspaces.len(), self.overrides.len(), self.whitelist.networks.len(), self.whitelist.ja3s.len());
+    }
 
-        let should_alert = self.update_or_insert_bucket(ja3, current_ts);
-    
-        if should_alert {
-            log::info!("Threshold violation, threshold: {} exceeded within {:?} seconds, for ja3: {}", self.args.threshold, self.args.window, ja3);
-            self.log_bucket(ja3);
-        } else {
-            self.periodic_cleanup(current_ts);
+    // check the source of a packet against the whitelisted networks, counting the skipped ones
+    pub fn skip_whitelisted(&mut self, source: &IpAddr) -> bool {
+        if self.whitelist.is_addr_whitelisted(source) {
+            log::debug!("{} is in whitelisted networks", source);
+            self.skipped += 1;
+            return true
         }
+        false
+    }
 
-        should_alert
+    // number of packets skipped so far because their source is whitelisted
+    pub fn skipped(&self) -> u64 {
+        self.skipped
     }
 
-    // fn should_skip_alert(&self, ja3: &str, current_ts: SystemTime) -> bool {
-    //     if let Some(last_alert_ts) = self.ja3_last_alerts.get(ja3) {
-    //         // Calculate the duration since the last alert
-    //         if let Ok(duration_since_last_alert) = current_ts.duration_since(*last_alert_ts) {
-    //             log::debug!("Last alerts for ja3: {}, was at: {:?}, elapsed time since then: {:?}", ja3, last_alert_ts, duration_since_last_alert);
-
-    //             // Check if the duration since the last alert is less than the window
-    //             duration_since_last_alert.as_secs() < self.args.window
-    //         } else {
-    //             // In case current_ts is before last_alert_ts, which is unlikely but should be handled
-    //             false
-    //         }
-    //     } else {
-    //         false
-    //     }
-    // }
-
-
-    fn update_or_insert_bucket(&mut self, key: &str, current_ts: SystemTime) -> bool {
-        let bucket = self.buckets
-            .entry(key.to_string())
-            .or_insert_with(|| {
-                let new_bucket = Bucket::new(key.to_string(), current_ts, self.bucket_window);
-                if let Some(true) = self.args.log_create_buckets {
-                    log::info!("Registering a new composite-key: {} as a bucket", key);
-                }
-                new_bucket
-            });
+    // process a packet against every keyspace, and return the alerts for the ones in violation, and the
+    // releases of blocked keys which have calmed down
+    pub fn process_event(&mut self, event: &PacketEvent) -> Vec<Alert> {
+        // every packet tracks its connection, a whitelisted ja3 still completes the handshake
+        let mut alerts = match self.connections.as_mut() {
+            Some(connections) => connections.process(event),
+            None => Vec::new(),
+        };
 
-        // increment the bucket for the timestamp ( which is now )
-        log::debug!("Troubleshooting window for key: {}", key);
-        bucket.update(current_ts);
+        // the ja3 whitelist covers the handshakes, the TCP control packets answer to the control threshold
+        if let Some(ja3) = event.ja3.as_deref() {
+            if self.whitelist.is_ja3_whitelisted(ja3) {
+                log::debug!("{} is in whitelisted ja3", ja3);
+                return alerts
+            }
+        }
 
-        // check if we tripped the threshold
-        bucket.check_threshold(self.args.threshold) &&
-            (bucket.last_alert_ts.is_none() ||
-                bucket.last_alert_ts.unwrap().elapsed().as_secs() > self.args.window)
+        // learn the ja3 first, so the keys of a novel one are held to the --novel-threshold
+        if let (Some(novelty), Some(ja3)) = (self.novelty.as_mut(), event.ja3.as_deref()) {
+            alerts.extend(novelty.process(ja3, event));
+        }
+        // the TCP control packets are counted apart from the handshakes
+        let metric = Metric::of(event.ja3.as_deref());
+        for idx in 0..self.keyspaces.len() {
+            let key = self.keyspaces[idx].template.render(event, self.args.agg_ipv6_prefix);
+            let limits = self.process_key(idx, metric, &key, event.ja3.as_deref(), event.ts);
+            let bucket = match self.keyspaces[idx].buckets_mut(metric).get_mut(&key) {
+                Some(bucket) => bucket,
+                None => continue,
+            };
+            bucket.observe(event.source, SocketAddr::new(event.destination, event.destination_port));
+            if let Some(limits) = limits {
+                let (count, evidence) = (bucket.rolling_window.sum(), bucket.evidence());
+                let alert = Alert {
+                    kind: AlertKind::Block,
+                    keyspace: self.keyspaces[idx].name.clone(),
+                    metric,
+                    key,
+                    ja3: event.ja3.clone(),
+                    source: event.source,
+                    count,
+                    threshold: limits.threshold,
+                    window: limits.window,
+                    block_seconds: limits.block_seconds,
+                    ts: event.ts,
+                    evidence,
+                };
+                if self.args.release_cooldown.is_some() {
+                    self.keyspaces[idx].block(&alert);
+                }
+                alerts.push(alert);
+            }
+        }
 
+        if let (Some(baseline), Some(ja3)) = (self.baseline.as_mut(), event.ja3.as_deref()) {
+            alerts.extend(baseline.process(ja3, event));
+        }
+        if let (Some(cardinality), Some(ja3)) = (self.cardinality.as_mut(), event.ja3.as_deref()) {
+            alerts.extend(cardinality.process(ja3, event));
+        }
+
+        if let Some(cooldown) = self.args.release_cooldown() {
+            for keyspace in self.keyspaces.iter_mut() {
+                alerts.extend(keyspace.releases(event.ts, cooldown));
+            }
+        }
+        alerts
     }
 
-    // cleanup buckets that are quiet every 2x window time
-    fn periodic_cleanup(&mut self, current_ts: SystemTime) {
+    // process a key in a keyspace, and return the limits it violated, if any
+    pub fn process_key(&mut self, keyspace: usize, metric: Metric, key: &str, ja3: Option<&str>, current_ts: SystemTime) -> Option<Limits> {
 
-        if let Ok(duration_since_last_cleanup) = current_ts.duration_since(self.last_cleanup) {
+        self.counter+=1;
+        // self.print_stats();
 
-            if duration_since_last_cleanup.as_secs() >= self.args.window * 2 {
-                let bucket_count_before = self.buckets.len();
-                self.cleanup_old_buckets(current_ts);
-                self.last_cleanup = SystemTime::now();
-                log::info!("Discarded idle buckets, count before: {}, count after: {}", bucket_count_before, self.buckets.len());
-            }
+        log::debug!("{} processing key: {}", self.counter, key);
 
+        let log_create_buckets = self.args.log_create_buckets == Some(true);
+        let limits = self.update_or_insert_bucket(keyspace, metric, key, ja3, current_ts, log_create_buckets);
+    
+        if let Some(limits) = limits {
+            let keyspace = &self.keyspaces[keyspace];
+            log::info!("Threshold violation, {} threshold: {} exceeded within {:?}, in keyspace: {}, for ja3: {}", metric.as_str(), limits.threshold, limits.window, keyspace.name, key);
+            self.log_bucket(keyspace, metric, key);
+        } else if self.keyspaces[keyspace].periodic_cleanup(current_ts) {
+            log::info!("Whitelisted packets skipped: {}", self.skipped);
+            self.log_current_state();
         }
 
+        limits
     }
 
-    // Cleanup function to remove old buckets.
-    fn cleanup_old_buckets(&mut self, current_ts: SystemTime) {
-        let expiration_duration = self.args.window * 2;
-
-        self.buckets.retain(|_, bucket| {
-            // Check if the bucket's last timestamp is within the expiration duration
-            if let Ok(elapsed) = current_ts.duration_since(bucket.last_ts) {
-                elapsed.as_secs() <= expiration_duration
-            } else {
-                // In case current_ts is before bucket.last_ts, retain this bucket
-                // This scenario is unlikely but should be handled
-                true
-            }
-        });
-
-        self.log_current_state();
+    // update the bucket against the limits for the key, the first matching override or the keyspace
+    // limits, and return them if they were violated
+    fn update_or_insert_bucket(&mut self, keyspace: usize, metric: Metric, key: &str, ja3: Option<&str>, current_ts: SystemTime, log_create_buckets: bool) -> Option<Limits> {
+        let keyspace = &mut self.keyspaces[keyspace];
+        let mut limits = limits_for(&self.overrides, key, ja3, keyspace.limits(metric));
+        if let Some(novelty) = &self.novelty {
+            limits = novelty.limits(ja3, current_ts, limits);
+        }
+        if keyspace.update_or_insert_bucket(metric, key, limits, current_ts, log_create_buckets) {
+            Some(limits)
+        } else {
+            None
+        }
     }
 
-
     // Logs the current state of the buckets. this is for local dev, and the iterator need to be left commented out for performance!
     pub fn log_current_state(&self) {
-        log::info!("Bucket count: {}", self.buckets.len());
-        for (key, bucket) in self.buckets.iter() {
-            if self.ja3_last_alerts.contains_key(key.split('-').next().unwrap_or_default())  {
-                log::info!("JA3: {}, Last Timestamp: {:?}, Rolling Count: {:?}, Total Count: {}",
-                    key, bucket.last_ts, bucket.rolling_window.window, bucket.sum_count);
+        for keyspace in self.keyspaces.iter() {
+            log::info!("Bucket count in keyspace: {}: {}, control: {}", keyspace.name, keyspace.buckets.len(), keyspace.control_buckets.len());
+            for (key, bucket) in keyspace.buckets.iter() {
+                if self.ja3_last_alerts.contains_key(key.split('-').next().unwrap_or_default())  {
+                    log::info!("JA3: {}, Last Timestamp: {:?}, Rolling Count: {:?}, Total Count: {}",
+                        key, bucket.last_ts, bucket.rolling_window.window, bucket.sum_count);
+                }
             }
         }
         // log::debug!("End of dump");
     }
 
-    pub fn log_bucket(&self, key: &str) {
-        match self.buckets.get(key) {
+    pub fn log_bucket(&self, keyspace: &Keyspace, metric: Metric, key: &str) {
+        match keyspace.buckets(metric).get(key) {
             Some(bucket) => {
-                let rolling_values: Vec<u32> = bucket
+                let rolling_values: Vec<u64> = bucket
                     .rolling_window
                     .window
                     .iter()
@@ -161,8 +306,10 @@ impl Monitor {
                 let total_count = bucket.rolling_window.sum();
 
                 log::info!(
-                    "Bucket key: {}, Last Timestamp: {:?}, Rolling Values: {:?}, Total Count: {}",
+                    "Bucket key: {}, Keyspace: {}, Metric: {}, Last Timestamp: {:?}, Rolling Values: {:?}, Total Count: {}",
                     key,
+                    keyspace.name,
+                    metric.as_str(),
                     bucket.last_ts,
                     rolling_values,
                     total_count
@@ -175,20 +322,22 @@ impl Monitor {
 
 
     pub fn _print_top_buckets(&self, top_n: usize) {
-        let mut buckets: Vec<_> = self.buckets.iter().collect();
-        // Sort buckets by sum_count in descending order
-        buckets.sort_by(|a, b| b.1.rolling_window.sum().cmp(&a.1.rolling_window.sum()));
-
-        log::info!("Top {} Buckets by sum_count:", top_n.min(buckets.len()));
-        for (key, bucket) in buckets.into_iter().take(top_n) {
-            log::info!("Key: {}, sum_count: {}", key, bucket.rolling_window.sum());
-            self.log_bucket(&key);
+        for keyspace in self.keyspaces.iter() {
+            let mut buckets: Vec<_> = keyspace.buckets.iter().collect();
+            // Sort buckets by sum_count in descending order
+            buckets.sort_by(|a, b| b.1.rolling_window.sum().cmp(&a.1.rolling_window.sum()));
+
+            log::info!("Top {} Buckets by sum_count in keyspace: {}:", top_n.min(buckets.len()), keyspace.name);
+            for (key, bucket) in buckets.into_iter().take(top_n) {
+                log::info!("Key: {}, sum_count: {}", key, bucket.rolling_window.sum());
+                self.log_bucket(keyspace, Metric::Handshake, key);
+            }
         }
     }
 
     pub fn print_stats(&mut self) {
         if self.last_counter_reset.elapsed() >= Duration::new(1, 0) {
-            log::info!("Key rate: {}", self.counter);
+            log::info!("Key rate: {}, whitelisted packets skipped: {}", self.counter, self.skipped);
             self.counter = 0;
             self.last_counter_reset = Instant::now();
         }
@@ -201,6 +350,7 @@ mod tests {
     use clap::Parser;
 
     use crate::args::AppArgs;
+    use crate::keyspace::KeyspaceSpec;
     use crate::whitelist::Whitelist;
 
     use super::*;
@@ -210,24 +360,15 @@ mod tests {
     #[test]
     fn test_new() {
         let args = AppArgs {
-            interface: Some("Foo".to_string()),           // Assuming "Foo" is a mock network device name
-            file: None,                            // No pcap file for testing
-            threshold: 1000,                            // Example threshold value
-            window: 60,                                  // Example window value in seconds
-            alert_url: "Foo".to_string(),                // Mock ELB host
-            dry_run: true,                 // Enable fake mode for testing
-            block_seconds: 86400,                       // Example block duration in seconds
-            whitelist_networks: "10.0.0.0/8, 192.168.0.0/16".to_string(), // Example whitelisted networks
-            whitelist_ja3s: "None".to_string(),                       // No whitelisted JA3 hashes for testing
-            log_create_buckets: Some(false),            // Disable logging for bucket creation in test
-            agg_ip: true                                // include IP in the key
+            agg_ip: true,
+            ..AppArgs::default()
         };
 
-        let nws = Arc::new(args.parse_whitelist_networks());
-        let ja3s = Arc::new(args.parse_whitelist_ja3());
+        let nws = Arc::new(args.parse_whitelist_networks().unwrap());
+        let ja3s = Arc::new(args.parse_whitelist_ja3().unwrap());
         let whitelist = Whitelist::new(nws, ja3s);
 
-        let md = Monitor::new(args);
+        let md = Monitor::new(args, whitelist);
         assert_eq!(md.args.threshold, 1000);
         assert_eq!(md.args.window, Duration::from_secs(60).as_secs());
         //... other assertions for initial state
@@ -237,57 +378,39 @@ mod tests {
     fn test_process_key_new_key() {
 
         let args = AppArgs {
-            interface: Some("Foo".to_string()),           // Assuming "Foo" is a mock network device name
-            file: None,                            // No pcap file for testing
-            threshold: 1000,                            // Example threshold value
-            window: 60,                                  // Example window value in seconds
-            alert_url: "Foo".to_string(),                // Mock ELB host
-            dry_run: true,                 // Enable fake mode for testing
-            block_seconds: 86400,                       // Example block duration in seconds
-            whitelist_networks: "10.0.0.0/8, 192.168.0.0/16".to_string(), // Example whitelisted networks
-            whitelist_ja3s: "None".to_string(),                       // No whitelisted JA3 hashes for testing
-            log_create_buckets: Some(false),            // Disable logging for bucket creation in test
-            agg_ip: true                                // include IP in the key
+            agg_ip: true,
+            ..AppArgs::default()
         };
 
-        let nws = Arc::new(args.parse_whitelist_networks());
-        let ja3s = Arc::new(args.parse_whitelist_ja3());
+        let nws = Arc::new(args.parse_whitelist_networks().unwrap());
+        let ja3s = Arc::new(args.parse_whitelist_ja3().unwrap());
         let whitelist = Whitelist::new(nws, ja3s);
 
-        let mut md = Monitor::new(args);
+        let mut md = Monitor::new(args, whitelist);
         let current_ts = SystemTime::now();
-        md.process_key("testkey", current_ts);
-        assert!(md.buckets.contains_key("testkey"));
+        md.process_key(0, Metric::Handshake, "testkey", None, current_ts);
+        assert!(md.keyspaces[0].buckets.contains_key("testkey"));
         //... other assertions for the state of 'testkey' bucket
     }
 
     #[test]
     fn test_process_key_existing_key() {
         let args = AppArgs {
-            interface: Some("Foo".to_string()),           // Assuming "Foo" is a mock network device name
-            file: None,                            // No pcap file for testing
-            threshold: 1000,                            // Example threshold value
-            window: 60,                                  // Example window value in seconds
-            alert_url: "Foo".to_string(),                // Mock ELB host
-            dry_run: true,                 // Enable fake mode for testing
-            block_seconds: 86400,                       // Example block duration in seconds
-            whitelist_networks: "10.0.0.0/8, 192.168.0.0/16".to_string(), // Example whitelisted networks
-            whitelist_ja3s: "None".to_string(),                       // No whitelisted JA3 hashes for testing
-            log_create_buckets: Some(false),            // Disable logging for bucket creation in test
-            agg_ip: true                                // include IP in the key
+            agg_ip: true,
+            ..AppArgs::default()
         };
-        let nws = Arc::new(args.parse_whitelist_networks());
-        let ja3s = Arc::new(args.parse_whitelist_ja3());
+        let nws = Arc::new(args.parse_whitelist_networks().unwrap());
+        let ja3s = Arc::new(args.parse_whitelist_ja3().unwrap());
         let whitelist = Whitelist::new(nws, ja3s);
 
-        let mut md = Monitor::new(args);
+        let mut md = Monitor::new(args, whitelist);
         let current_ts = SystemTime::now();
-        md.process_key("testkey", current_ts);
+        md.process_key(0, Metric::Handshake, "testkey", None, current_ts);
         // simulate some time passage
         let new_ts = current_ts + Duration::from_secs(30);
-        md.process_key("testkey", new_ts);
+        md.process_key(0, Metric::Handshake, "testkey", None, new_ts);
         //... assertions for updated state of 'testkey' bucket
-        match md.buckets.get("testkey") {
+        match md.keyspaces[0].buckets.get("testkey") {
             Some(value) => {
                 println!("Value for key '{:?}'", value.last_ts);
                 assert!(value.last_ts == new_ts);
@@ -301,28 +424,341 @@ mod tests {
     #[test]
     fn test_cleanup_old_buckets() {
         let args = AppArgs {
-            interface: Some("Foo".to_string()),           // Assuming "Foo" is a mock network device name
-            file: None,                            // No pcap file for testing
-            threshold: 1000,                            // Example threshold value
-            window: 60,                                  // Example window value in seconds
-            alert_url: "Foo".to_string(),                // Mock ELB host
-            dry_run: true,                 // Enable fake mode for testing
-            block_seconds: 86400,                       // Example block duration in seconds
-            whitelist_networks: "10.0.0.0/8, 192.168.0.0/16".to_string(), // Example whitelisted networks
-            whitelist_ja3s: "None".to_string(),                       // No whitelisted JA3 hashes for testing
-            log_create_buckets: Some(false),            // Disable logging for bucket creation in test
-            agg_ip: true                                // include IP in the key
+            agg_ip: true,
+            ..AppArgs::default()
         };
-        let nws = Arc::new(args.parse_whitelist_networks());
-        let ja3s = Arc::new(args.parse_whitelist_ja3());
+        let nws = Arc::new(args.parse_whitelist_networks().unwrap());
+        let ja3s = Arc::new(args.parse_whitelist_ja3().unwrap());
         let whitelist = Whitelist::new(nws, ja3s);
-        let mut md = Monitor::new(args);
+        let mut md = Monitor::new(args, whitelist);
         let current_ts = SystemTime::now();
-        md.process_key("oldkey", current_ts - Duration::from_secs(500));
-        md.process_key("newkey", current_ts);
-        md.cleanup_old_buckets(current_ts);
-        assert!(!md.buckets.contains_key("oldkey"));
-        assert!(md.buckets.contains_key("newkey"));
+        md.process_key(0, Metric::Handshake, "oldkey", None, current_ts - Duration::from_secs(500));
+        md.process_key(0, Metric::Handshake, "newkey", None, current_ts);
+        md.keyspaces[0].cleanup_old_buckets(current_ts);
+        assert!(!md.keyspaces[0].buckets.contains_key("oldkey"));
+        assert!(md.keyspaces[0].buckets.contains_key("newkey"));
+    }
+
+    #[test]
+    fn test_process_key_replayed_timestamps() {
+        let args = AppArgs {
+            file: Some("capture.pcap".to_string()),
+            threshold: 10,
+            agg_ip: true,
+            ..AppArgs::default()
+        };
+        let nws = Arc::new(args.parse_whitelist_networks().unwrap());
+        let ja3s = Arc::new(args.parse_whitelist_ja3().unwrap());
+        let whitelist = Whitelist::new(nws, ja3s);
+        let mut md = Monitor::new(args, whitelist);
+
+        // a capture from a week ago, one handshake every 10 seconds stays under the threshold
+        let start = SystemTime::now() - Duration::from_secs(7 * 86400);
+        let mut alerts = 0;
+        for i in 0..60 {
+            if md.process_key(0, Metric::Handshake, "slowkey", None, start + Duration::from_secs(i * 10)).is_some() {
+                alerts += 1;
+            }
+        }
+        assert_eq!(alerts, 0);
+
+        // a burst within the same second trips once, and is not re-alerted within the window
+        let burst_ts = start + Duration::from_secs(1000);
+        for _ in 0..50 {
+            if md.process_key(0, Metric::Handshake, "burstkey", None, burst_ts).is_some() {
+                alerts += 1;
+            }
+        }
+        assert_eq!(alerts, 1);
+        assert_eq!(md.keyspaces[0].buckets.get("burstkey").unwrap().last_alert_ts, Some(burst_ts));
+
+        // idle buckets are cleaned up by capture time, not wall clock
+        md.process_key(0, Metric::Handshake, "burstkey", None, burst_ts + Duration::from_secs(500));
+        assert!(!md.keyspaces[0].buckets.contains_key("slowkey"));
+    }
+
+    #[test]
+    fn test_skip_whitelisted() {
+        let args = AppArgs {
+            agg_ip: true,
+            ..AppArgs::default()
+        };
+        let nws = Arc::new(args.parse_whitelist_networks().unwrap());
+        let ja3s = Arc::new(args.parse_whitelist_ja3().unwrap());
+        let whitelist = Whitelist::new(nws, ja3s);
+        let mut md = Monitor::new(args, whitelist);
+
+        assert!(md.skip_whitelisted(&"10.1.2.3".parse().unwrap()));
+        assert!(md.skip_whitelisted(&"192.168.0.7".parse().unwrap()));
+        assert!(!md.skip_whitelisted(&"8.8.8.8".parse().unwrap()));
+        assert_eq!(md.skipped(), 2);
+    }
+
+    #[test]
+    fn test_process_event_keyspaces() {
+        let args = AppArgs {
+            keyspace: vec![
+                KeyspaceSpec::parse("ja3={ja3},threshold=20").unwrap(),
+                KeyspaceSpec::parse("per_ip={src},threshold=5,block_seconds=600").unwrap(),
+            ],
+            ..AppArgs::default()
+        };
+        let nws = Arc::new(args.parse_whitelist_networks().unwrap());
+        let ja3s = Arc::new(args.parse_whitelist_ja3().unwrap());
+        let whitelist = Whitelist::new(nws, ja3s);
+        let mut md = Monitor::new(args, whitelist);
+
+        // one address hammering with a single ja3 trips the per_ip keyspace first
+        let ts = SystemTime::now();
+        let mut alerts = Vec::new();
+        for i in 0..10 {
+            let event = PacketEvent {
+                ja3: Some(format!("{:032x}", i % 2)),
+                ..PacketEvent::test(ts)
+            };
+            alerts.extend(md.process_event(&event));
+        }
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].keyspace, "per_ip");
+        assert_eq!(alerts[0].key, "8.8.8.8");
+        assert_eq!(alerts[0].block_seconds, 600);
+        assert_eq!(alerts[0].window, Duration::from_secs(60));
+        assert_eq!(md.keyspaces[0].buckets.len(), 2);
+        assert_eq!(md.keyspaces[1].buckets.len(), 1);
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut args = AppArgs {
+            agg_ip: true,
+            keyspace: vec![
+                KeyspaceSpec::parse("ja3={ja3}").unwrap(),
+                KeyspaceSpec::parse("per_ip={src}").unwrap(),
+            ],
+            ..AppArgs::default()
+        };
+        let whitelist = args.whitelist().unwrap();
+        let mut md = Monitor::new(args.clone(), whitelist);
+
+        let ts = SystemTime::now();
+        md.process_key(0, Metric::Handshake, "testkey", None, ts);
+        md.process_key(1, Metric::Handshake, "8.8.8.8", None, ts);
+        assert!(!md.skip_whitelisted(&"8.8.8.8".parse().unwrap()));
+
+        // lower the threshold, drop a keyspace and whitelist 8.8.8.0/24
+        args.threshold = 1;
+        args.keyspace = vec![KeyspaceSpec::parse("ja3={ja3}").unwrap()];
+        args.whitelist_networks = "8.8.8.0/24".to_string();
+        let whitelist = args.whitelist().unwrap();
+        md.reload(args, whitelist);
+
+        assert_eq!(md.keyspaces.len(), 1);
+        assert_eq!(md.keyspaces[0].threshold, 1);
+        assert!(md.keyspaces[0].buckets.contains_key("testkey"));
+        assert!(md.skip_whitelisted(&"8.8.8.8".parse().unwrap()));
+
+        // the bucket state survived, so the next hit trips the new threshold
+        assert!(md.process_key(0, Metric::Handshake, "testkey", None, ts).is_some());
+    }
+
+    #[test]
+    fn test_process_event_overrides() {
+        let args = AppArgs {
+            agg_ip: true,
+            overrides: vec![Override::parse("ja3=e7d705a3286e19ea42f587b344ee6865,threshold=3,window=10,block_seconds=604800").unwrap()],
+            ..AppArgs::default()
+        };
+        let whitelist = args.whitelist().unwrap();
+        let mut md = Monitor::new(args, whitelist);
+
+        let ts = SystemTime::now();
+        let mut alerts = Vec::new();
+        for ja3 in ["e7d705a3286e19ea42f587b344ee6865", "579ccef312d18482fc42e2b822ca2430"] {
+            for _ in 0..5 {
+                let event = PacketEvent {
+                    ja3: Some(ja3.to_string()),
+                    ..PacketEvent::test(ts)
+                };
+                alerts.extend(md.process_event(&event));
+            }
+        }
+
+        // only the overridden ja3 trips, with the overridden window and block time
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].key, "e7d705a3286e19ea42f587b344ee6865-8.8.8.8");
+        assert_eq!(alerts[0].window, Duration::from_secs(10));
+        assert_eq!(alerts[0].ja3.as_deref(), Some("e7d705a3286e19ea42f587b344ee6865"));
+        assert_eq!(alerts[0].count, 4);
+        assert_eq!(alerts[0].threshold, 3);
+        assert_eq!(alerts[0].block_seconds, 604800);
+        assert_eq!(alerts[0].evidence.sources, vec!["8.8.8.8".parse::<IpAddr>().unwrap()]);
+        assert_eq!(alerts[0].evidence.destinations, vec![SocketAddr::from(([192, 168, 0, 1], 443))]);
+        assert_eq!(alerts[0].evidence.histogram, vec![(ts, 4)]);
+        assert_eq!(md.keyspaces[0].buckets.get("e7d705a3286e19ea42f587b344ee6865-8.8.8.8").unwrap().window, Duration::from_secs(10));
+        assert_eq!(md.keyspaces[0].buckets.get("579ccef312d18482fc42e2b822ca2430-8.8.8.8").unwrap().window, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_process_event_control() {
+        let args = AppArgs {
+            threshold: 3,
+            control_threshold: Some(6),
+            agg_ip: true,
+            keyspace: vec![KeyspaceSpec::parse("per_ip={src}").unwrap()],
+            ..AppArgs::default()
+        };
+        let whitelist = args.whitelist().unwrap();
+        let mut md = Monitor::new(args, whitelist);
+
+        let ts = SystemTime::now();
+        let event = |ja3: Option<&str>, rst: bool| PacketEvent {
+            ja3: ja3.map(str::to_string),
+            flags: TcpFlags { rst, ..TcpFlags::default() },
+            ..PacketEvent::test(ts)
+        };
+
+        // a NAT gateway's resets stay under the control threshold, and don't add to its handshakes
+        let mut alerts = Vec::new();
+        for _ in 0..6 {
+            alerts.extend(md.process_event(&event(None, true)));
+        }
+        for _ in 0..3 {
+            alerts.extend(md.process_event(&event(Some("579ccef312d18482fc42e2b822ca2430"), false)));
+        }
+        assert!(alerts.is_empty());
+        assert_eq!(md.keyspaces[0].control_buckets.get("8.8.8.8").unwrap().rolling_window.sum(), 6);
+        assert_eq!(md.keyspaces[0].buckets.get("8.8.8.8").unwrap().rolling_window.sum(), 3);
+
+        let alerts = md.process_event(&event(None, true));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].metric, Metric::Control);
+        assert_eq!((alerts[0].key.as_str(), alerts[0].count, alerts[0].threshold), ("8.8.8.8", 7, 6));
+
+        let alerts = md.process_event(&event(Some("579ccef312d18482fc42e2b822ca2430"), false));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].metric, Metric::Handshake);
+        assert_eq!((alerts[0].count, alerts[0].threshold), (4, 3));
+    }
+
+    #[test]
+    fn test_process_event_control_default_template() {
+        let args = AppArgs {
+            control_threshold: Some(3),
+            ..AppArgs::default()
+        };
+        let whitelist = args.whitelist().unwrap();
+        let mut md = Monitor::new(args, whitelist);
+
+        // the {ja3} template keeps the control packets of each source apart
+        let ts = SystemTime::now();
+        let mut alerts = Vec::new();
+        for source in ["8.8.8.8", "8.8.4.4"] {
+            for _ in 0..3 {
+                alerts.extend(md.process_event(&PacketEvent { ja3: None, source: source.parse().unwrap(), ..PacketEvent::test(ts) }));
+            }
+        }
+        assert!(alerts.is_empty());
+
+        let alerts = md.process_event(&PacketEvent { ja3: None, ..PacketEvent::test(ts) });
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].metric, Metric::Control);
+        assert_eq!((alerts[0].key.as_str(), alerts[0].count), ("None-8.8.8.8", 4));
+    }
+
+    #[test]
+    fn test_process_event_control_whitelisted_ja3() {
+        // the ja3 whitelist drops the handshakes, the control packets are counted all the same
+        let args = AppArgs {
+            threshold: 3,
+            control_threshold: Some(6),
+            whitelist_ja3s: TEST_JA3.to_string(),
+            agg_ip: true,
+            ..AppArgs::default()
+        };
+        let whitelist = args.whitelist().unwrap();
+        let mut md = Monitor::new(args, whitelist);
+
+        let ts = SystemTime::now();
+        let mut alerts = Vec::new();
+        for _ in 0..7 {
+            alerts.extend(md.process_event(&PacketEvent::test(ts)));
+            alerts.extend(md.process_event(&PacketEvent { ja3: None, ..PacketEvent::test(ts) }));
+        }
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].metric, Metric::Control);
+        assert!(md.keyspaces[0].buckets.is_empty());
+    }
+
+    #[test]
+    fn test_process_event_connections() {
+        let args = AppArgs {
+            conn_half_open_limit: Some(2),
+            agg_ip: true,
+            ..AppArgs::default()
+        };
+        let whitelist = args.whitelist().unwrap();
+        let mut md = Monitor::new(args, whitelist);
+
+        // SYNs without a ja3 count as control packets in the keyspaces, and are tracked
+        let ts = SystemTime::now();
+        let mut alerts = Vec::new();
+        for port in 50000..50003 {
+            let event = PacketEvent {
+                ja3: None,
+                source_port: port,
+                flags: TcpFlags { syn: true, ..TcpFlags::default() },
+                ..PacketEvent::test(ts)
+            };
+            alerts.extend(md.process_event(&event));
+        }
+        assert!(alerts.is_empty());
+        assert!(md.keyspaces[0].buckets.is_empty());
+        assert_eq!(md.keyspaces[0].control_buckets.get("None-8.8.8.8").unwrap().rolling_window.sum(), 3);
+
+        let event = PacketEvent {
+            ja3: None,
+            source: "8.8.4.4".parse().unwrap(),
+            ..PacketEvent::test(ts + Duration::from_secs(6))
+        };
+        let alerts = md.process_event(&event);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].keyspace, "connections");
+        assert_eq!(alerts[0].key, "half_open-8.8.8.8");
+    }
+
+    #[test]
+    fn test_process_event_novel() {
+        let path = std::env::temp_dir().join(format!("susspekt-monitor-known-ja3-test-{}.jsonl", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+        let args = AppArgs {
+            known_ja3_file: Some(path.clone()),
+            novel_training: 0,
+            novel_threshold: Some(3),
+            agg_ip: true,
+            ..AppArgs::default()
+        };
+        let whitelist = args.whitelist().unwrap();
+        let mut md = Monitor::new(args, whitelist);
+
+        let ts = SystemTime::now();
+        let mut alerts = Vec::new();
+        for _ in 0..5 {
+            let event = PacketEvent {
+                ja3: Some("e7d705a3286e19ea42f587b344ee6865".to_string()),
+                ..PacketEvent::test(ts)
+            };
+            alerts.extend(md.process_event(&event));
+        }
+
+        // a novel alert for the first handshake, then its key trips the novel threshold
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(alerts[0].kind, AlertKind::Novel);
+        assert_eq!(alerts[0].keyspace, "novel");
+        assert_eq!(alerts[1].kind, AlertKind::Block);
+        assert_eq!(alerts[1].key, "e7d705a3286e19ea42f587b344ee6865-8.8.8.8");
+        assert_eq!(alerts[1].threshold, 3);
+        std::fs::remove_file(&path).unwrap();
     }
 
     // Additional tests for other methods and scenarios...
@@ -0,0 +1,258 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::alert::{Alert, AlertKind, Evidence, Metric};
+use crate::monitor::PacketEvent;
+use crate::overrides::Limits;
+
+/**
+ * Fingerprints which never touched the edge before. The known ja3s are kept in the --known-ja3-file as
+ * JSON lines with the time they were first seen, loaded at startup and appended to as new ones show up.
+ * For the --novel-training after the first ja3 ever recorded new ja3s are learned without alerting, after
+ * that a new ja3 sends a novel alert, which is informational and blocks nothing. With --novel-threshold
+ * the keys of a novel ja3 are held to that lower threshold for the --novel-period after it was first
+ * seen, so one which ramps up quickly is blocked early.
+ */
+
+pub const NOVEL_KEYSPACE: &str = "novel";
+
+// the most ja3s remembered, so fingerprint randomisation can't grow the set without bound
+const MAX_KNOWN: usize = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoveltySettings {
+    pub path: String,
+    pub training: Duration, // from the first ja3 recorded, new ja3s are learned silently
+    pub period: Duration, // after a novel ja3 is first seen, its keys are held to the threshold
+    pub threshold: Option<u64>,
+}
+
+// a line of the known ja3 file
+#[derive(Serialize, Deserialize, Debug)]
+struct KnownJa3 {
+    ja3: String,
+    first_seen: String, // RFC 3339
+}
+
+pub(crate) struct NoveltyDetector {
+    settings: NoveltySettings,
+    known: HashMap<String, SystemTime>, // first seen per ja3
+    started: Option<SystemTime>, // the first ja3 recorded, training runs from here
+    full: bool,
+}
+
+impl NoveltyDetector {
+    // load the known ja3s, unreadable lines are logged and skipped rather than failing startup
+    pub fn open(settings: NoveltySettings) -> Self {
+        let mut known = HashMap::new();
+        match fs::read_to_string(&settings.path) {
+            Ok(content) => {
+                for (n, line) in content.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                    match parse_line(line) {
+                        Ok((ja3, first_seen)) => { known.entry(ja3).or_insert(first_seen); },
+                        Err(e) => log::error!("Skipping unreadable entry on line: {} of known ja3 file: {}, {}", n + 1, settings.path, e),
+                    }
+                }
+                log::info!("Loaded {} known ja3s from: {}", known.len(), settings.path);
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => log::error!("Unable to read known ja3 file: {}, {}", settings.path, e),
+        }
+        NoveltyDetector {
+            started: known.values().min().copied(),
+            full: known.len() >= MAX_KNOWN,
+            settings,
+            known,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.settings.path
+    }
+
+    // swap in reloaded settings for the same file, keeping the known ja3s
+    pub fn reconfigure(&mut self, settings: NoveltySettings) {
+        self.settings = settings;
+    }
+
+    fn training_over(&self, ts: SystemTime) -> bool {
+        self.started.is_some_and(|started| ts >= started + self.settings.training)
+    }
+
+    // record the ja3 of a handshake, and return a novel alert the first time one is seen after the training
+    pub fn process(&mut self, ja3: &str, event: &PacketEvent) -> Option<Alert> {
+        if self.known.contains_key(ja3) {
+            return None;
+        }
+        if self.full {
+            return None;
+        }
+        if self.known.len() >= MAX_KNOWN {
+            log::error!("Known ja3s full at {}, new ja3s are no longer recorded or alerted", MAX_KNOWN);
+            self.full = true;
+            return None;
+        }
+
+        self.started.get_or_insert(event.ts);
+        self.known.insert(ja3.to_string(), event.ts);
+        self.append(ja3, event.ts);
+        if !self.training_over(event.ts) {
+            log::debug!("Learned ja3: {} in training", ja3);
+            return None;
+        }
+
+        log::info!("Novel ja3: {} first seen from: {}", ja3, event.source);
+        Some(Alert {
+            kind: AlertKind::Novel,
+            keyspace: NOVEL_KEYSPACE.to_string(),
+            metric: Metric::Handshake,
+            key: ja3.to_string(),
+            ja3: Some(ja3.to_string()),
+            source: event.source,
+            count: 1,
+            threshold: 0,
+            window: self.settings.period,
+            block_seconds: 0,
+            ts: event.ts,
+            evidence: Evidence {
+                sources: vec![event.source],
+                destinations: vec![SocketAddr::new(event.destination, event.destination_port)],
+                first_seen: Some(event.ts),
+                last_seen: Some(event.ts),
+                ..Evidence::default()
+            },
+        })
+    }
+
+    // the limits for a key of the ja3, lowered to the --novel-threshold while the ja3 is novel
+    pub fn limits(&self, ja3: Option<&str>, ts: SystemTime, limits: Limits) -> Limits {
+        let threshold = match self.settings.threshold {
+            Some(threshold) if threshold < limits.threshold => threshold,
+            _ => return limits,
+        };
+        let first_seen = match ja3.and_then(|ja3| self.known.get(ja3)) {
+            Some(first_seen) => *first_seen,
+            None => return limits,
+        };
+        // ja3s learned in training are not novel
+        if !self.training_over(first_seen) {
+            return limits;
+        }
+        match ts.duration_since(first_seen) {
+            Ok(age) if age >= self.settings.period => limits,
+            _ => Limits { threshold, ..limits },
+        }
+    }
+
+    // append rather than rewrite, new ja3s can come in bursts and the file only grows
+    fn append(&self, ja3: &str, first_seen: SystemTime) {
+        let record = KnownJa3 {
+            ja3: ja3.to_string(),
+            first_seen: DateTime::<Utc>::from(first_seen).to_rfc3339_opts(SecondsFormat::Millis, true),
+        };
+        let result = serde_json::to_string(&record)
+            .map_err(std::io::Error::from)
+            .and_then(|line| {
+                let mut file = OpenOptions::new().create(true).append(true).open(&self.settings.path)?;
+                writeln!(file, "{}", line)
+            });
+        if let Err(e) = result {
+            log::error!("Unable to write known ja3 file: {}, {}", self.settings.path, e);
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Result<(String, SystemTime), String> {
+    let record: KnownJa3 = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let first_seen = DateTime::parse_from_rfc3339(&record.first_seen).map_err(|e| e.to_string())?;
+    Ok((record.ja3, first_seen.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::monitor::{test_start, TEST_JA3 as JA3};
+
+    const NEW_JA3: &str = "e7d705a3286e19ea42f587b344ee6865";
+
+    fn settings(path: &str) -> NoveltySettings {
+        NoveltySettings {
+            path: path.to_string(),
+            training: Duration::from_secs(3600),
+            period: Duration::from_secs(600),
+            threshold: Some(10),
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("susspekt-{}-test-{}.jsonl", name, std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_training_and_novel() {
+        let path = temp_path("known-ja3");
+        let start = test_start();
+        let mut detector = NoveltyDetector::open(settings(&path));
+
+        // learned silently in training
+        assert!(detector.process(JA3, &PacketEvent::test(start)).is_none());
+        assert!(detector.process(JA3, &PacketEvent::test(start + Duration::from_secs(7200))).is_none());
+
+        // a new one after the training alerts once
+        let ts = start + Duration::from_secs(7200);
+        let alert = detector.process(NEW_JA3, &PacketEvent::test(ts)).unwrap();
+        assert_eq!(alert.kind, AlertKind::Novel);
+        assert_eq!((alert.keyspace.as_str(), alert.key.as_str()), (NOVEL_KEYSPACE, NEW_JA3));
+        assert_eq!(alert.block_seconds, 0);
+        assert!(detector.process(NEW_JA3, &PacketEvent::test(ts + Duration::from_secs(1))).is_none());
+
+        // kept across a restart, with training over
+        let reopened = NoveltyDetector::open(settings(&path));
+        assert_eq!(reopened.known.len(), 2);
+        assert_eq!(reopened.known[NEW_JA3], ts);
+        assert!(reopened.training_over(ts));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_novel_limits() {
+        let path = temp_path("novel-limits");
+        let start = test_start();
+        let mut detector = NoveltyDetector::open(NoveltySettings { training: Duration::ZERO, ..settings(&path) });
+        let limits = Limits { threshold: 1000, window: Duration::from_secs(60), block_seconds: 600 };
+
+        assert!(detector.process(NEW_JA3, &PacketEvent::test(start)).is_some());
+        assert_eq!(detector.limits(Some(NEW_JA3), start + Duration::from_secs(60), limits).threshold, 10);
+        assert_eq!(detector.limits(Some(NEW_JA3), start + Duration::from_secs(600), limits), limits);
+        assert_eq!(detector.limits(Some(JA3), start, limits), limits);
+        assert_eq!(detector.limits(None, start, limits), limits);
+
+        // a lower threshold of the keyspace or override wins
+        let strict = Limits { threshold: 5, ..limits };
+        assert_eq!(detector.limits(Some(NEW_JA3), start, strict), strict);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unreadable_lines() {
+        let path = temp_path("known-ja3-unreadable");
+        fs::write(&path, format!("{{\"ja3\":\"{}\",\"first_seen\":\"2023-11-27T09:14:25.000Z\"}}\nnot json\n", JA3)).unwrap();
+        let detector = NoveltyDetector::open(settings(&path));
+        assert_eq!(detector.known.len(), 1);
+        assert_eq!(detector.started, Some(test_start()));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::Write;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/**
+ * Alerts which could not be delivered, replayed in order once the endpoint recovers. With a path the
+ * queue is kept on disk as JSON lines, rewritten on every change, so pending alerts survive a restart.
+ * The queue is bounded, the oldest entries are dropped when it is full.
+ */
+
+pub const MAX_OUTBOX: usize = 10000;
+
+pub struct Outbox<T> {
+    path: Option<String>,
+    entries: VecDeque<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Outbox<T> {
+    // open the outbox, loading the entries left over from a previous run. unreadable lines are logged
+    // and skipped rather than failing startup.
+    pub fn open(path: Option<String>) -> Self {
+        let mut entries = VecDeque::new();
+        if let Some(path) = &path {
+            match fs::read_to_string(path) {
+                Ok(content) => {
+                    for (n, line) in content.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+                        match serde_json::from_str(line) {
+                            Ok(entry) => entries.push_back(entry),
+                            Err(e) => log::error!("Skipping unreadable entry on line: {} of outbox: {}, {}", n + 1, path, e),
+                        }
+                    }
+                    if !entries.is_empty() {
+                        log::warn!("Loaded {} undelivered alerts from outbox: {}", entries.len(), path);
+                    }
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => log::error!("Unable to read outbox: {}, {}", path, e),
+            }
+        }
+        Outbox { path, entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.entries.iter()
+    }
+
+    pub fn push(&mut self, entry: T) {
+        if self.entries.len() >= MAX_OUTBOX {
+            log::error!("Outbox full, dropping the oldest undelivered alert");
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        self.persist();
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        let entry = self.entries.pop_front();
+        self.persist();
+        entry
+    }
+
+    // rewrite the file through a temporary one, so a crash mid write doesn't lose the queue
+    fn persist(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let tmp = format!("{}.tmp", path);
+        let result = fs::File::create(&tmp)
+            .and_then(|mut file| {
+                for entry in self.entries.iter() {
+                    let line = serde_json::to_string(entry).map_err(std::io::Error::from)?;
+                    writeln!(file, "{}", line)?;
+                }
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, path));
+        if let Err(e) = result {
+            log::error!("Unable to write outbox: {}, {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("susspekt-outbox-test-{}.jsonl", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let _ = fs::remove_file(&path);
+
+        let mut outbox: Outbox<String> = Outbox::open(Some(path.clone()));
+        outbox.push("first".to_string());
+        outbox.push("second".to_string());
+
+        let mut reopened: Outbox<String> = Outbox::open(Some(path.clone()));
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.pop_front(), Some("first".to_string()));
+
+        let reopened: Outbox<String> = Outbox::open(Some(path.clone()));
+        assert_eq!(reopened.iter().next(), Some(&"second".to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+}
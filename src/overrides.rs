@@ -0,0 +1,168 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+/**
+ * Per ja3 (or key prefix) overrides of the threshold, window and block time. Known heavy but legit
+ * clients can be given a higher limit, and known bad tooling can trip after a handful of handshakes.
+ * The first matching override wins, unset values fall back to the keyspace.
+ */
+
+use std::time::Duration;
+
+// The limits a bucket is checked against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub threshold: u64,
+    pub window: Duration,
+    pub block_seconds: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Matcher {
+    Ja3(String), // the md5 ja3 hash of the packet, regardless of the key template
+    KeyPrefix(String), // the start of the rendered key
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub matcher: Matcher,
+    pub threshold: Option<u64>,
+    pub window: Option<Duration>,
+    pub block_seconds: Option<u32>,
+}
+
+impl Matcher {
+    pub fn ja3(ja3: &str) -> Result<Matcher, String> {
+        if ja3.len() == 32 && ja3.chars().all(|c| c.is_ascii_hexdigit()) {
+            Ok(Matcher::Ja3(ja3.to_ascii_lowercase()))
+        } else {
+            Err(format!("invalid override ja3 '{}': expected a 32 character md5 hex digest", ja3))
+        }
+    }
+
+    pub fn key_prefix(prefix: &str) -> Result<Matcher, String> {
+        if prefix.is_empty() {
+            Err("override key_prefix must not be empty".to_string())
+        } else {
+            Ok(Matcher::KeyPrefix(prefix.to_string()))
+        }
+    }
+}
+
+impl Override {
+    // parse an override as given on the command line, e.g:
+    // ja3=579ccef312d18482fc42e2b822ca2430,threshold=5,block_seconds=604800 or key_prefix=None-,threshold=50000
+    // the window is given in seconds with window=, or in milliseconds with window_ms=
+    pub fn parse(spec: &str) -> Result<Override, String> {
+        let mut options = spec.split(',');
+        let matcher = match options.next().and_then(|s| s.split_once('=')) {
+            Some(("ja3", ja3)) => Matcher::ja3(ja3.trim())?,
+            Some(("key_prefix", prefix)) => Matcher::key_prefix(prefix.trim())?,
+            _ => return Err(format!("override '{}' should start with ja3=<hash> or key_prefix=<prefix>", spec)),
+        };
+
+        let mut parsed = Override {
+            matcher,
+            threshold: None,
+            window: None,
+            block_seconds: None,
+        };
+
+        for option in options {
+            let (k, v) = option.split_once('=')
+                .ok_or_else(|| format!("override option '{}' should be key=value in '{}'", option, spec))?;
+            let v = v.trim();
+            match k.trim() {
+                "threshold" => parsed.threshold = Some(v.parse().map_err(|e| format!("invalid threshold '{}' in override '{}': {}", v, spec, e))?),
+                "window" => parsed.window = Some(Duration::from_secs(v.parse().map_err(|e| format!("invalid window '{}' in override '{}': {}", v, spec, e))?)),
+                "window_ms" => parsed.window = Some(Duration::from_millis(v.parse().map_err(|e| format!("invalid window_ms '{}' in override '{}': {}", v, spec, e))?)),
+                "block_seconds" => parsed.block_seconds = Some(v.parse().map_err(|e| format!("invalid block_seconds '{}' in override '{}': {}", v, spec, e))?),
+                other => return Err(format!("unknown override option '{}' in '{}', expected threshold, window, window_ms or block_seconds", other, spec)),
+            }
+        }
+
+        parsed.validate().map_err(|e| format!("{} in override '{}'", e, spec))?;
+        Ok(parsed)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.window == Some(Duration::ZERO) {
+            return Err("window must be greater than zero".to_string());
+        }
+        if self.threshold.is_none() && self.window.is_none() && self.block_seconds.is_none() {
+            return Err("no threshold, window or block_seconds given".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, key: &str, ja3: Option<&str>) -> bool {
+        match &self.matcher {
+            Matcher::Ja3(hash) => ja3 == Some(hash.as_str()),
+            Matcher::KeyPrefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+// the limits for a key, from the first matching override, falling back to the defaults
+pub fn limits_for(overrides: &[Override], key: &str, ja3: Option<&str>, defaults: Limits) -> Limits {
+    match overrides.iter().find(|o| o.matches(key, ja3)) {
+        Some(o) => Limits {
+            threshold: o.threshold.unwrap_or(defaults.threshold),
+            window: o.window.unwrap_or(defaults.window),
+            block_seconds: o.block_seconds.unwrap_or(defaults.block_seconds),
+        },
+        None => defaults,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JA3: &str = "579ccef312d18482fc42e2b822ca2430";
+
+    #[test]
+    fn test_parse() {
+        let o = Override::parse(&format!("ja3={},threshold=5,block_seconds=604800", JA3.to_uppercase())).unwrap();
+        assert_eq!(o.matcher, Matcher::Ja3(JA3.to_string()));
+        assert_eq!(o.threshold, Some(5));
+        assert_eq!(o.window, None);
+        assert_eq!(o.block_seconds, Some(604800));
+
+        let o = Override::parse("key_prefix=None-,window=10").unwrap();
+        assert_eq!(o.matcher, Matcher::KeyPrefix("None-".to_string()));
+        assert_eq!(o.window, Some(Duration::from_secs(10)));
+
+        let o = Override::parse("key_prefix=None-,window_ms=250").unwrap();
+        assert_eq!(o.window, Some(Duration::from_millis(250)));
+
+        let o = Override::parse("key_prefix=None-,threshold=5000000").unwrap();
+        assert_eq!(o.threshold, Some(5000000));
+
+        assert!(Override::parse("ja3=nothex,threshold=5").is_err());
+        assert!(Override::parse("src=1.2.3.4,threshold=5").is_err());
+        assert!(Override::parse(&format!("ja3={}", JA3)).is_err());
+        assert!(Override::parse(&format!("ja3={},window=0", JA3)).is_err());
+        assert!(Override::parse(&format!("ja3={},window_ms=0", JA3)).is_err());
+        assert!(Override::parse(&format!("ja3={},colour=red", JA3)).is_err());
+    }
+
+    #[test]
+    fn test_limits_for() {
+        let defaults = Limits { threshold: 1000, window: Duration::from_secs(60), block_seconds: 86400 };
+        let overrides = vec![
+            Override::parse(&format!("ja3={},threshold=5", JA3)).unwrap(),
+            Override::parse("key_prefix=None-,threshold=50000,window=10").unwrap(),
+        ];
+
+        let limits = limits_for(&overrides, &format!("{}-1.2.3.4", JA3), Some(JA3), defaults);
+        assert_eq!(limits, Limits { threshold: 5, window: Duration::from_secs(60), block_seconds: 86400 });
+
+        let limits = limits_for(&overrides, "None-1.2.3.4", None, defaults);
+        assert_eq!(limits, Limits { threshold: 50000, window: Duration::from_secs(10), block_seconds: 86400 });
+
+        let limits = limits_for(&overrides, "0123456789abcdef0123456789abcdef", Some("0123456789abcdef0123456789abcdef"), defaults);
+        assert_eq!(limits, defaults);
+    }
+}
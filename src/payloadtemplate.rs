@@ -0,0 +1,172 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::fmt;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/**
+ * Templates for the requests of an http sink, e.g. a body of `{"ip": {{address}}, "ttl": {{block_time}}}`
+ * or a path of `/v1/blocks/{{address}}`. Placeholders use double braces so JSON can be written as is.
+ *
+ * In a body a placeholder is replaced by the JSON value of the field, quoted for strings and as an
+ * array for lists. In a path it is replaced by the plain text, percent encoded, and in a header by the
+ * plain text. The fields are those of the default payload, plus:
+ *
+ *   {{address}}   the address or network at the end of the key, e.g. 192.168.0.7, null without one
+ *   {{cidr}}      the same in CIDR notation, e.g. 192.168.0.7/32
+ */
+
+pub const FIELDS: &[&str] = &[
+    "schema_version", "key", "keyspace", "block_time", "realert", "event", "metric", "offence", "ja3", "sources",
+    "destinations", "count", "threshold", "window_ms", "first_seen", "last_seen", "slot_ms", "histogram",
+    "distinct_sources", "distinct_networks", "address", "cidr",
+];
+
+// how a value is written into the template
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escape {
+    Json,
+    Path,
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct PayloadTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl PayloadTemplate {
+    // Compile a template, failing on unknown fields or an unterminated placeholder
+    pub fn parse(template: &str) -> Result<PayloadTemplate, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..].find("}}")
+                .ok_or_else(|| format!("unterminated placeholder in template '{}'", template))?;
+            let field = rest[start + 2..start + end].trim();
+            if !FIELDS.contains(&field) {
+                return Err(format!("unknown field '{{{{{}}}}}' in template '{}', expected one of: {}", field, template, FIELDS.join(", ")));
+            }
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            parts.push(Part::Field(field.to_string()));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(PayloadTemplate { template: template.to_string(), parts })
+    }
+
+    // whether the template has any placeholders, or renders the same for every alert
+    pub fn has_fields(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Field(_)))
+    }
+
+    // Render the template with the fields of an alert
+    pub fn render(&self, fields: &Map<String, Value>, escape: Escape) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Field(field) => {
+                    let value = fields.get(field).unwrap_or(&Value::Null);
+                    match escape {
+                        Escape::Json => rendered.push_str(&value.to_string()),
+                        Escape::Path => rendered.push_str(&percent_encode(&text(value))),
+                        Escape::Text => rendered.push_str(&text(value)),
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+impl TryFrom<String> for PayloadTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        PayloadTemplate::parse(&template)
+    }
+}
+
+impl fmt::Display for PayloadTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+// a value as plain text, strings unquoted, null empty and lists comma separated
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values.iter().map(text).collect::<Vec<_>>().join(","),
+        other => other.to_string(),
+    }
+}
+
+// percent encode everything but the unreserved characters, and the colons of IPv6 addresses
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fields() -> Map<String, Value> {
+        match json!({
+            "key": "579ccef312d18482fc42e2b822ca2430-2001:db8::/64",
+            "block_time": 600,
+            "sources": ["2001:db8::1", "2001:db8::2"],
+            "address": "2001:db8::/64",
+            "ja3": null,
+        }) {
+            Value::Object(fields) => fields,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = PayloadTemplate::parse(r#"{"ip": {{ address }}, "ttl": {{block_time}}, "from": {{sources}}, "ja3": {{ja3}}}"#).unwrap();
+        let body: Value = serde_json::from_str(&template.render(&fields(), Escape::Json)).unwrap();
+        assert_eq!(body, json!({"ip": "2001:db8::/64", "ttl": 600, "from": ["2001:db8::1", "2001:db8::2"], "ja3": null}));
+
+        let template = PayloadTemplate::parse("/v1/blocks/{{address}}").unwrap();
+        assert_eq!(template.render(&fields(), Escape::Path), "/v1/blocks/2001:db8::%2F64");
+
+        let template = PayloadTemplate::parse("susspekt {{key}} from {{sources}}").unwrap();
+        assert_eq!(template.render(&fields(), Escape::Text), "susspekt 579ccef312d18482fc42e2b822ca2430-2001:db8::/64 from 2001:db8::1,2001:db8::2");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(PayloadTemplate::parse(r#"{"ip": {{address}"#).is_err());
+        assert!(PayloadTemplate::parse(r#"{"ip": {{ip}}}"#).unwrap_err().contains("unknown field '{{ip}}'"));
+        assert!(!PayloadTemplate::parse(r#"{"static": true}"#).unwrap().has_fields());
+        assert!(PayloadTemplate::parse("/v1/blocks/{{address}}").unwrap().has_fields());
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/**
+ * Exponential backoff with jitter for alert delivery. The delay doubles every attempt up to a cap,
+ * and a random part of it is shaved off so a fleet of sensors doesn't retry in lockstep against a
+ * recovering endpoint.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32, // attempts after the first one
+    pub backoff: Duration, // the delay before the first retry
+    pub max_backoff: Duration, // the cap on the delay
+}
+
+impl RetryPolicy {
+    // the jittered delay before retry number `attempt`, counting from 0. between half and all of
+    // backoff * 2^attempt, capped at max_backoff
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let half = delay / 2;
+        let jitter = random() % (half.as_nanos() as u64 + 1);
+        half + Duration::from_nanos(jitter)
+    }
+}
+
+// run an operation until it succeeds or the retries run out, backing off between the attempts
+pub async fn with_retries<T, E, F, Fut>(policy: &RetryPolicy, what: &str, mut op: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Ok(result) => return Ok(result),
+            Err(e) if attempt < policy.retries => {
+                let delay = policy.delay(attempt);
+                log::warn!("Error {}, retrying in {:?}, {}", what, delay, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// std only randomness, each RandomState is seeded with fresh random keys
+fn random() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            retries: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+        };
+        for _ in 0..100 {
+            let first = policy.delay(0);
+            assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100), "{:?}", first);
+            let third = policy.delay(2);
+            assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400), "{:?}", third);
+            let capped = policy.delay(20);
+            assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_millis(1000), "{:?}", capped);
+        }
+    }
+}
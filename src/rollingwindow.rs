@@ -6,25 +6,37 @@ use std::collections::VecDeque;
 use std::time::Duration;
 use std::time::SystemTime;
 
+// the most slots a window may be split into, a day of one second slots. bounds the memory per bucket.
+pub const MAX_SLOTS: usize = 86400;
+
+// the number of slots of the given length needed to cover the window
+pub fn slots(window: Duration, slot: Duration) -> usize {
+    window.as_nanos().div_ceil(slot.as_nanos().max(1)) as usize
+}
+
 #[derive(Debug)]
 pub struct RollingWindow {
-    pub window: VecDeque<(SystemTime, u32)>,
+    pub window: VecDeque<(SystemTime, u64)>,
+    duration: Duration,
+    slot: Duration,
     capacity: usize,
 }
 
 impl RollingWindow {
-    pub fn new(capacity: usize) -> RollingWindow {
+    pub fn new(duration: Duration, slot: Duration) -> RollingWindow {
         RollingWindow {
-            window: VecDeque::with_capacity(capacity),
-            capacity,
+            window: VecDeque::new(), // grows to at most capacity slots, so idle buckets stay small
+            duration,
+            slot,
+            capacity: slots(duration, slot),
         }
     }
 
-    pub fn update(&mut self, value: u32, timestamp: SystemTime) {
-        // Remove values older than capacity seconds
+    pub fn update(&mut self, value: u64, timestamp: SystemTime) {
+        // Remove values older than the window duration
         while self.window.front().map_or(false, |&(t, _)| {
             if let Ok(duration) = timestamp.duration_since(t) {
-                duration > Duration::new(self.capacity as u64, 0)
+                duration > self.duration
             } else {
                 false
             }
@@ -32,25 +44,37 @@ impl RollingWindow {
             self.window.pop_front();
         }
 
-        // Check if the last entry in the window has the same second as the timestamp
+        // Check if the last entry in the window is in the same slot as the timestamp
         if let Some(&mut (t, ref mut last_value)) = self.window.back_mut() {
             if let Ok(duration) = timestamp.duration_since(t) {
-                if duration.as_secs() == 0 {
-                    // If the timestamps are in the same second, merge the value
+                if duration < self.slot {
+                    // If the timestamps are in the same slot, merge the value
                     *last_value += value;
                     return;
                 }
             }
         }
 
-        // If the timestamp is new or the window is empty, add the new value
-        if self.window.len() < self.capacity {
-            self.window.push_back((timestamp, value));
+        // If the timestamp is new or the window is empty, add the new value, making room by
+        // evicting the oldest slot rather than dropping the newest value
+        if self.window.len() >= self.capacity {
+            self.window.pop_front();
+        }
+        self.window.push_back((timestamp, value));
+    }
+
+    // change the duration and slot, e.g. on a config reload, dropping the oldest values which no longer fit
+    pub fn resize(&mut self, duration: Duration, slot: Duration) {
+        self.duration = duration;
+        self.slot = slot;
+        self.capacity = slots(duration, slot);
+        while self.window.len() > self.capacity {
+            self.window.pop_front();
         }
     }
 
-    pub fn sum(&self) -> u16 {
-        self.window.iter().map(|&(_, count)| count as u16).sum()
+    pub fn sum(&self) -> u64 {
+        self.window.iter().map(|&(_, count)| count).sum()
     }
 }
 
@@ -59,16 +83,18 @@ mod tests {
     use super::*;
     use std::thread::sleep;
 
+    const SECOND: Duration = Duration::from_secs(1);
+
     #[test]
     fn test_initialization() {
-        let window = RollingWindow::new(60);
+        let window = RollingWindow::new(Duration::from_secs(60), SECOND);
         assert!(window.window.is_empty());
         assert_eq!(window.capacity, 60);
     }
 
     #[test]
     fn test_single_update() {
-        let mut window = RollingWindow::new(60);
+        let mut window = RollingWindow::new(Duration::from_secs(60), SECOND);
         window.update(10, SystemTime::now());
         assert_eq!(window.window.len(), 1);
         assert_eq!(window.sum(), 10);
@@ -76,7 +102,7 @@ mod tests {
 
     #[test]
     fn test_multiple_updates_within_capacity() {
-        let mut window = RollingWindow::new(60);
+        let mut window = RollingWindow::new(Duration::from_secs(60), SECOND);
 
 
         // multiple updates in same instant should be one object in the window
@@ -100,7 +126,7 @@ mod tests {
     #[test]
     fn test_capacity_limit() {
         let ts = SystemTime::now();
-        let mut window = RollingWindow::new(60);
+        let mut window = RollingWindow::new(Duration::from_secs(60), SECOND);
         for i in 0..100 {
             window.update(1, ts + Duration::new(i.try_into().unwrap(), 0));
         }
@@ -108,9 +134,75 @@ mod tests {
         assert_eq!(window.sum(), 60);
     }
 
+    #[test]
+    fn test_full_window_evicts_oldest() {
+        let ts = SystemTime::now();
+        let mut window = RollingWindow::new(Duration::from_secs(60), SECOND);
+        for i in 0..60 {
+            window.update(1, ts + Duration::new(i, 0));
+        }
+        assert_eq!(window.window.len(), window.capacity);
+
+        // the first slot is exactly a window old and still counts, the new value replaces it
+        window.update(5, ts + Duration::new(60, 0));
+        assert_eq!(window.window.len(), 60);
+        assert_eq!(window.window.front().unwrap().0, ts + SECOND);
+        assert_eq!(window.window.back().unwrap(), &(ts + Duration::new(60, 0), 5));
+        assert_eq!(window.sum(), 64);
+    }
+
+    #[test]
+    fn test_sum_beyond_u16() {
+        let ts = SystemTime::now();
+        let mut window = RollingWindow::new(Duration::from_secs(60), SECOND);
+        for i in 0..60 {
+            window.update(5000, ts + Duration::new(i, 0));
+        }
+        window.update(u32::MAX as u64, ts + Duration::new(59, 0));
+        assert_eq!(window.sum(), 300000 + u32::MAX as u64);
+    }
+
+    #[test]
+    fn test_sub_second_slots() {
+        let ts = SystemTime::now();
+        let mut window = RollingWindow::new(Duration::from_millis(500), Duration::from_millis(100));
+        assert_eq!(window.capacity, 5);
+
+        // a burst of 500 handshakes in 200ms lands in two slots
+        for i in 0..500 {
+            window.update(1, ts + Duration::from_micros(i * 400));
+        }
+        assert_eq!(window.window.len(), 2);
+        assert_eq!(window.sum(), 500);
+
+        // and has left the window 700ms later
+        window.update(1, ts + Duration::from_millis(900));
+        assert_eq!(window.sum(), 1);
+    }
+
+    #[test]
+    fn test_slots() {
+        assert_eq!(slots(Duration::from_secs(60), SECOND), 60);
+        assert_eq!(slots(Duration::from_millis(250), Duration::from_millis(100)), 3);
+        assert_eq!(slots(Duration::from_millis(50), Duration::from_millis(100)), 1);
+    }
+
+    #[test]
+    fn test_resize() {
+        let ts = SystemTime::now();
+        let mut window = RollingWindow::new(Duration::from_secs(60), SECOND);
+        for i in 0..30 {
+            window.update(1, ts + Duration::new(i, 0));
+        }
+        window.resize(Duration::from_secs(10), SECOND);
+        assert_eq!(window.capacity, 10);
+        assert_eq!(window.window.len(), 10);
+        assert_eq!(window.window.front().unwrap().0, ts + Duration::new(20, 0));
+    }
+
     #[test]
     fn test_time_based_removal() {
-        let mut window = RollingWindow::new(5);
+        let mut window = RollingWindow::new(Duration::from_secs(5), SECOND);
         let start = SystemTime::now();
         window.update(1, start);
         sleep(Duration::new(6, 0));  // Sleep for 6 seconds
@@ -120,7 +212,7 @@ mod tests {
 
     #[test]
     fn test_sparse_update() {
-        let mut window = RollingWindow::new(5);
+        let mut window = RollingWindow::new(Duration::from_secs(5), SECOND);
         let start = SystemTime::now();
         window.update(1, start);
         sleep(Duration::new(10, 0));  // Sleep for 10 seconds
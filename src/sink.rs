@@ -0,0 +1,365 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::alert::Alert;
+use crate::args::AppArgs;
+use crate::filesink::FileSink;
+use crate::firewallsink::{Backend, FirewallSettings, FirewallSink};
+use crate::httpauth::{HttpAuthConfig, Secret};
+use crate::payloadtemplate::PayloadTemplate;
+use crate::poster::HttpPoster;
+use crate::retry::RetryPolicy;
+use crate::syslogsink::{SyslogProtocol, SyslogSink, DEFAULT_FACILITY};
+
+/**
+ * Alert sinks. Every alert is fanned out to each sink whose filter it passes, e.g. the block API, a
+ * local JSONL audit file, a syslog collector and the local firewall at the same time. A sink owns its retry policy and
+ * dry-run flag. Sinks are configured with [[sinks]] in the config file, without any the alerts are
+ * posted to --alert-url as before.
+ */
+
+#[async_trait]
+pub trait AlertSink: Send {
+    fn name(&self) -> &str;
+
+    // called once before the first alert, e.g. to reconcile state left by a previous run
+    async fn start(&mut self) {}
+
+    // deliver an alert, retrying as the sink sees fit
+    async fn send(&mut self, alert: &Alert) -> Result<(), String>;
+
+    // periodic housekeeping, e.g. replaying undelivered alerts
+    async fn tick(&mut self) {}
+
+    // called once the alerts stop, e.g. to flush what is buffered
+    async fn stop(&mut self) {}
+
+    fn tick_interval(&self) -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+// Which alerts a sink receives, empty lists match everything
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SinkFilter {
+    pub keyspaces: Vec<String>,
+    pub key_prefixes: Vec<String>,
+}
+
+impl SinkFilter {
+    pub fn matches(&self, alert: &Alert) -> bool {
+        (self.keyspaces.is_empty() || self.keyspaces.contains(&alert.keyspace)) &&
+            (self.key_prefixes.is_empty() || self.key_prefixes.iter().any(|prefix| alert.key.starts_with(prefix.as_str())))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SinkKind {
+    Http,
+    File,
+    Syslog,
+    Nftables,
+    Ipset,
+}
+
+impl SinkKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SinkKind::Http => "http",
+            SinkKind::File => "file",
+            SinkKind::Syslog => "syslog",
+            SinkKind::Nftables => "nftables",
+            SinkKind::Ipset => "ipset",
+        }
+    }
+
+    // the options specific to a kind of sink, and which of them are required
+    fn options(&self) -> (&'static [&'static str], &'static [&'static str]) {
+        match self {
+            SinkKind::Http => (&["url", "outbox", "batch_size", "batch_delay_ms", "method", "url_path", "headers", "body",
+                "bearer_token", "basic_auth", "hmac_secret", "client_cert", "client_key", "ca_cert"], &[]),
+            SinkKind::File => (&["path"], &["path"]),
+            SinkKind::Syslog => (&["address", "protocol", "facility"], &["address"]),
+            SinkKind::Nftables => (&["set", "set6", "family", "table", "command", "state"], &["set"]),
+            SinkKind::Ipset => (&["set", "set6", "command", "state"], &["set"]),
+        }
+    }
+}
+
+// A [[sinks]] entry of the config file, unset retry and dry-run values fall back to the --alert-* options
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SinkConfig {
+    #[serde(rename = "type")]
+    pub kind: SinkKind,
+    pub name: Option<String>,
+    pub url: Option<String>, // http, defaults to --alert-url
+    pub path: Option<String>, // file
+    pub dry_run: Option<bool>,
+    pub retries: Option<u32>,
+    pub backoff_ms: Option<u64>,
+    pub backoff_max_ms: Option<u64>,
+    pub outbox: Option<String>, // http
+    pub batch_size: Option<usize>, // http, defaults to --alert-batch-size
+    pub batch_delay_ms: Option<u64>, // http
+    pub method: Option<String>, // http, defaults to POST
+    pub url_path: Option<PayloadTemplate>, // http, appended to the url
+    #[serde(default)]
+    pub headers: BTreeMap<String, PayloadTemplate>, // http
+    pub body: Option<PayloadTemplate>, // http, defaults to the JSON payload
+    pub bearer_token: Option<Secret>, // http, env:NAME or file:PATH
+    pub basic_auth: Option<Secret>, // http, user:password from env:NAME or file:PATH
+    pub hmac_secret: Option<Secret>, // http, signs the body
+    pub client_cert: Option<String>, // http, PEM file for mutual TLS
+    pub client_key: Option<String>, // http, PKCS#8 PEM file
+    pub ca_cert: Option<String>, // http, PEM bundle of extra CAs to trust
+    pub address: Option<String>, // syslog, host:port or the path of a unix socket
+    pub protocol: Option<SyslogProtocol>, // syslog, udp, tcp or unix, defaults to udp
+    pub facility: Option<u8>, // syslog, defaults to local0
+    pub set: Option<String>, // nftables and ipset, the set of blocked IPv4 addresses
+    pub set6: Option<String>, // nftables and ipset, IPv6 addresses are not blocked without it
+    pub family: Option<String>, // nftables, defaults to inet
+    pub table: Option<String>, // nftables, defaults to filter
+    pub command: Option<String>, // nftables and ipset, the path of nft or ipset
+    pub state: Option<String>, // nftables and ipset, the blocks to re-apply on startup
+    #[serde(default)]
+    pub keyspaces: Vec<String>,
+    #[serde(default)]
+    pub key_prefixes: Vec<String>,
+}
+
+impl SinkConfig {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.kind.as_str().to_string())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let set = [
+            ("url", self.url.is_some()),
+            ("path", self.path.is_some()),
+            ("outbox", self.outbox.is_some()),
+            ("batch_size", self.batch_size.is_some()),
+            ("batch_delay_ms", self.batch_delay_ms.is_some()),
+            ("method", self.method.is_some()),
+            ("url_path", self.url_path.is_some()),
+            ("headers", !self.headers.is_empty()),
+            ("body", self.body.is_some()),
+            ("bearer_token", self.bearer_token.is_some()),
+            ("basic_auth", self.basic_auth.is_some()),
+            ("hmac_secret", self.hmac_secret.is_some()),
+            ("client_cert", self.client_cert.is_some()),
+            ("client_key", self.client_key.is_some()),
+            ("ca_cert", self.ca_cert.is_some()),
+            ("address", self.address.is_some()),
+            ("protocol", self.protocol.is_some()),
+            ("facility", self.facility.is_some()),
+            ("set", self.set.is_some()),
+            ("set6", self.set6.is_some()),
+            ("family", self.family.is_some()),
+            ("table", self.table.is_some()),
+            ("command", self.command.is_some()),
+            ("state", self.state.is_some()),
+        ];
+        let (allowed, required) = self.kind.options();
+        for (option, is_set) in set {
+            if is_set && !allowed.contains(&option) {
+                return Err(format!("sink '{}': {} is not an option of {} sinks", self.name(), option, self.kind.as_str()));
+            }
+            if !is_set && required.contains(&option) {
+                return Err(format!("sink '{}': {} sinks need the {} option", self.name(), self.kind.as_str(), option));
+            }
+        }
+        if self.facility.is_some_and(|facility| facility > 23) {
+            return Err(format!("sink '{}': facility must be 0-23", self.name()));
+        }
+        if let Some(method) = &self.method {
+            reqwest::Method::from_bytes(method.to_uppercase().as_bytes())
+                .map_err(|_| format!("sink '{}': invalid method '{}'", self.name(), method))?;
+        }
+        for header in self.headers.keys() {
+            reqwest::header::HeaderName::from_bytes(header.as_bytes())
+                .map_err(|_| format!("sink '{}': invalid header name '{}'", self.name(), header))?;
+        }
+        if self.batch_size == Some(0) || self.batch_delay_ms == Some(0) {
+            return Err(format!("sink '{}': batch_size and batch_delay_ms must be at least 1", self.name()));
+        }
+        if self.backoff_max_ms == Some(0) {
+            return Err(format!("sink '{}': backoff_max_ms must be at least 1", self.name()));
+        }
+        Ok(())
+    }
+
+    // a batch is one request to the url, so nothing but the body can depend on the alert
+    pub fn validate_batching(&self, default_batch_size: usize) -> Result<(), String> {
+        let batching = self.batch_size.unwrap_or(default_batch_size) > 1;
+        if batching && (self.url_path.is_some() || self.headers.values().any(PayloadTemplate::has_fields)) {
+            return Err(format!("sink '{}': url_path and header placeholders can't be used when batching alerts", self.name()));
+        }
+        Ok(())
+    }
+
+    pub fn filter(&self) -> SinkFilter {
+        SinkFilter {
+            keyspaces: self.keyspaces.clone(),
+            key_prefixes: self.key_prefixes.clone(),
+        }
+    }
+
+    // the credentials and TLS options, a credential or client certificate set on the sink replaces the
+    // --alert-* one as a whole so a bearer token and basic auth never mix
+    pub fn http_auth(&self, defaults: HttpAuthConfig) -> HttpAuthConfig {
+        let own_credential = self.bearer_token.is_some() || self.basic_auth.is_some();
+        let own_identity = self.client_cert.is_some() || self.client_key.is_some();
+        HttpAuthConfig {
+            bearer_token: if own_credential { self.bearer_token.clone() } else { defaults.bearer_token },
+            basic_auth: if own_credential { self.basic_auth.clone() } else { defaults.basic_auth },
+            hmac_secret: self.hmac_secret.clone().or(defaults.hmac_secret),
+            client_cert: if own_identity { self.client_cert.clone() } else { defaults.client_cert },
+            client_key: if own_identity { self.client_key.clone() } else { defaults.client_key },
+            ca_cert: self.ca_cert.clone().or(defaults.ca_cert),
+        }
+    }
+
+    pub fn retry_policy(&self, defaults: RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.retries.unwrap_or(defaults.retries),
+            backoff: self.backoff_ms.map_or(defaults.backoff, Duration::from_millis),
+            max_backoff: self.backoff_max_ms.map_or(defaults.max_backoff, Duration::from_millis),
+        }
+    }
+}
+
+// the sinks to fan the alerts out to, the --alert-url poster unless [[sinks]] are configured
+pub fn build(args: &AppArgs) -> Vec<(Box<dyn AlertSink>, SinkFilter)> {
+    if args.sinks.is_empty() {
+        return vec![(Box::new(HttpPoster::new(args.clone())), SinkFilter::default())];
+    }
+    args.sinks.iter()
+        .map(|config| {
+            let sink: Box<dyn AlertSink> = match config.kind {
+                SinkKind::Http => Box::new(HttpPoster::for_sink(args.clone(), config)),
+                SinkKind::File => Box::new(FileSink::new(
+                    config,
+                    config.dry_run.unwrap_or(args.dry_run),
+                    config.retry_policy(args.retry_policy()),
+                )),
+                SinkKind::Syslog => Box::new(SyslogSink::new(
+                    config.name(),
+                    config.address.clone().unwrap_or_default(),
+                    config.protocol.unwrap_or(SyslogProtocol::Udp),
+                    config.facility.unwrap_or(DEFAULT_FACILITY),
+                    config.dry_run.unwrap_or(args.dry_run),
+                    config.retry_policy(args.retry_policy()),
+                )),
+                SinkKind::Nftables | SinkKind::Ipset => Box::new(FirewallSink::new(
+                    config.name(),
+                    FirewallSettings {
+                        backend: if config.kind == SinkKind::Nftables { Backend::Nftables } else { Backend::Ipset },
+                        set: config.set.clone().unwrap_or_default(),
+                        set6: config.set6.clone(),
+                        family: config.family.clone().unwrap_or_else(|| "inet".to_string()),
+                        table: config.table.clone().unwrap_or_else(|| "filter".to_string()),
+                        command: config.command.clone(),
+                        state: config.state.clone(),
+                    },
+                    config.dry_run.unwrap_or(args.dry_run),
+                    config.retry_policy(args.retry_policy()),
+                )),
+            };
+            (sink, config.filter())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn alert(keyspace: &str, key: &str) -> Alert {
+        Alert { keyspace: keyspace.to_string(), ..Alert::test(key, SystemTime::now()) }
+    }
+
+    #[test]
+    fn test_filter() {
+        assert!(SinkFilter::default().matches(&alert("default", "579ccef312d18482fc42e2b822ca2430")));
+
+        let filter = SinkFilter {
+            keyspaces: vec!["per_ip".to_string()],
+            key_prefixes: vec!["None-".to_string()],
+        };
+        assert!(filter.matches(&alert("per_ip", "None-1.2.3.4")));
+        assert!(!filter.matches(&alert("default", "None-1.2.3.4")));
+        assert!(!filter.matches(&alert("per_ip", "579ccef312d18482fc42e2b822ca2430-1.2.3.4")));
+    }
+
+    #[test]
+    fn test_http_auth() {
+        let defaults = HttpAuthConfig {
+            bearer_token: Some(Secret::Env("SUSSPEKT_TOKEN".to_string())),
+            hmac_secret: Some(Secret::Env("SUSSPEKT_HMAC".to_string())),
+            ca_cert: Some("/etc/susspekt/ca.pem".to_string()),
+            ..Default::default()
+        };
+        let config: SinkConfig = toml::from_str("type = \"http\"").unwrap();
+        assert_eq!(config.http_auth(defaults.clone()), defaults);
+
+        let config: SinkConfig = toml::from_str("type = \"http\"\nbasic_auth = \"file:/etc/susspekt/vendor\"").unwrap();
+        let auth = config.http_auth(defaults);
+        assert_eq!(auth.bearer_token, None);
+        assert_eq!(auth.basic_auth, Some(Secret::File("/etc/susspekt/vendor".to_string())));
+        assert_eq!(auth.hmac_secret, Some(Secret::Env("SUSSPEKT_HMAC".to_string())));
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: SinkConfig = toml::from_str("type = \"file\"").unwrap();
+        assert!(config.validate().unwrap_err().contains("file sinks need the path option"));
+
+        let config: SinkConfig = toml::from_str("type = \"file\"\npath = \"a.jsonl\"\nurl = \"http://localhost\"").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: SinkConfig = toml::from_str("type = \"http\"\nretries = 5").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.name(), "http");
+
+        let config: SinkConfig = toml::from_str("type = \"syslog\"\nprotocol = \"tcp\"").unwrap();
+        assert!(config.validate().unwrap_err().contains("syslog sinks need the address option"));
+
+        let config: SinkConfig = toml::from_str("type = \"syslog\"\naddress = \"127.0.0.1:514\"\nfacility = 24").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: SinkConfig = toml::from_str("type = \"ipset\"\nset = \"susspekt4\"\ntable = \"filter\"").unwrap();
+        assert!(config.validate().unwrap_err().contains("table is not an option of ipset sinks"));
+
+        let config: SinkConfig = toml::from_str("type = \"http\"\nmethod = \"PUT\"\nurl_path = \"/blocks/{{address}}\"\nheaders = { X-Key = \"{{key}}\" }").unwrap();
+        config.validate().unwrap();
+
+        let config: SinkConfig = toml::from_str("type = \"http\"\nmethod = \"P O S T\"").unwrap();
+        assert!(config.validate().unwrap_err().contains("invalid method"));
+
+        let config: SinkConfig = toml::from_str("type = \"file\"\npath = \"a.jsonl\"\nbody = \"{}\"").unwrap();
+        assert!(config.validate().unwrap_err().contains("body is not an option of file sinks"));
+
+        let config: SinkConfig = toml::from_str("type = \"syslog\"\naddress = \"127.0.0.1:514\"\nhmac_secret = \"env:SUSSPEKT_HMAC\"").unwrap();
+        assert!(config.validate().unwrap_err().contains("hmac_secret is not an option of syslog sinks"));
+
+        let config: SinkConfig = toml::from_str("type = \"http\"\nbatch_size = 100\nurl_path = \"/blocks/{{address}}\"").unwrap();
+        config.validate().unwrap();
+        assert!(config.validate_batching(1).unwrap_err().contains("can't be used when batching"));
+        let config: SinkConfig = toml::from_str("type = \"http\"\nheaders = { X-Api-Key = \"secret\" }").unwrap();
+        config.validate_batching(100).unwrap();
+
+        assert!(toml::from_str::<SinkConfig>("type = \"http\"\nbody = \"{{ip}}\"").unwrap_err().to_string().contains("unknown field '{{ip}}'"));
+        assert!(toml::from_str::<SinkConfig>("type = \"carrier-pigeon\"").is_err());
+        assert!(toml::from_str::<SinkConfig>("type = \"syslog\"\nprotocol = \"smtp\"").is_err());
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright 2023 Kegan Holtzhausen
+// Licensed under the MIT License (https://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according to those terms.
+
+use std::net::IpAddr;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket, UnixDatagram};
+
+use crate::alert::{Alert, AlertKind, Metric};
+use crate::retry::RetryPolicy;
+use crate::sink::AlertSink;
+
+/**
+ * Alerts as RFC 5424 syslog messages with a CEF payload, for SIEMs which ingest syslog. Sent over UDP,
+ * TCP with octet counting framing (RFC 6587), or a unix datagram socket such as /dev/log. e.g:
+ *
+ *   <132>1 2023-11-27T09:14:25.288Z sensor1 susspekt 4242 alert - CEF:0|susspekt|susspekt|0.1.0|threshold|
+ *   Handshake threshold exceeded|7|cs1Label=key cs1=579ccef312d18482fc42e2b822ca2430-192.168.0.7 ...
+ */
+
+pub const DEFAULT_FACILITY: u8 = 16; // local0
+const SEVERITY: u8 = 4; // warning
+const RELEASE_SEVERITY: u8 = 5; // notice
+const NOVEL_SEVERITY: u8 = 6; // informational
+const CEF_SEVERITY: u8 = 7;
+const CEF_RELEASE_SEVERITY: u8 = 3;
+const CEF_NOVEL_SEVERITY: u8 = 4;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogProtocol {
+    Udp,
+    Tcp,
+    Unix,
+}
+
+pub struct SyslogSink {
+    name: String,
+    address: String,
+    protocol: SyslogProtocol,
+    facility: u8,
+    dry_run: bool,
+    retry: RetryPolicy,
+    hostname: String,
+    udp: Option<UdpSocket>,
+    tcp: Option<TcpStream>, // kept open between alerts, reconnected after a failed write
+    unix: Option<UnixDatagram>,
+}
+
+impl SyslogSink {
+    pub fn new(name: String, address: String, protocol: SyslogProtocol, facility: u8, dry_run: bool, retry: RetryPolicy) -> Self {
+        SyslogSink {
+            name,
+            address,
+            protocol,
+            facility,
+            dry_run,
+            retry,
+            hostname: hostname(),
+            udp: None,
+            tcp: None,
+            unix: None,
+        }
+    }
+
+    // the RFC 5424 message for an alert
+    fn format(&self, alert: &Alert) -> String {
+        let severity = match alert.kind {
+            AlertKind::Block => SEVERITY,
+            AlertKind::Release => RELEASE_SEVERITY,
+            AlertKind::Novel => NOVEL_SEVERITY,
+        };
+        format!("<{}>1 {} {} susspekt {} alert - {}",
+            self.facility as u16 * 8 + severity as u16,
+            DateTime::<Utc>::from(alert.ts).to_rfc3339_opts(SecondsFormat::Millis, true),
+            self.hostname,
+            std::process::id(),
+            cef(alert))
+    }
+
+    async fn write(&mut self, message: &str) -> std::io::Result<()> {
+        match self.protocol {
+            SyslogProtocol::Udp => {
+                if self.udp.is_none() {
+                    let target = tokio::net::lookup_host(&self.address).await?.next()
+                        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address"))?;
+                    let bind = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+                    let socket = UdpSocket::bind(bind).await?;
+                    socket.connect(target).await?;
+                    self.udp = Some(socket);
+                }
+                if let Some(socket) = &self.udp {
+                    socket.send(message.as_bytes()).await?;
+                }
+            },
+            SyslogProtocol::Tcp => {
+                if self.tcp.is_none() {
+                    self.tcp = Some(TcpStream::connect(&self.address).await?);
+                }
+                if let Some(stream) = &mut self.tcp {
+                    let framed = format!("{} {}", message.len(), message);
+                    if let Err(e) = stream.write_all(framed.as_bytes()).await {
+                        self.tcp = None;
+                        return Err(e);
+                    }
+                }
+            },
+            SyslogProtocol::Unix => {
+                if self.unix.is_none() {
+                    self.unix = Some(UnixDatagram::unbound()?);
+                }
+                if let Some(socket) = &self.unix {
+                    socket.send_to(message.as_bytes(), &self.address).await?;
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AlertSink for SyslogSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn send(&mut self, alert: &Alert) -> Result<(), String> {
+        let message = self.format(alert);
+        if self.dry_run {
+            log::info!("DryRun, not sending to syslog {}: {}", self.address, message);
+            return Ok(());
+        }
+
+        // the connection is reused between attempts, so this can't go through retry::with_retries
+        let mut attempt = 0;
+        loop {
+            match self.write(&message).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.retry.retries => {
+                    let delay = self.retry.delay(attempt);
+                    log::warn!("Error sending alert for key: {} to syslog {}, retrying in {:?}, {}", alert.key, self.address, delay, e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                },
+                Err(e) => return Err(format!("unable to send to syslog {}: {}", self.address, e)),
+            }
+        }
+    }
+}
+
+// the CEF payload of an alert
+fn cef(alert: &Alert) -> String {
+    let source = match alert.source {
+        IpAddr::V4(_) => format!("src={}", alert.source),
+        IpAddr::V6(_) => format!("c6a2={}", alert.source),
+    };
+    let (signature, name, severity) = match (alert.kind, alert.metric) {
+        (AlertKind::Block, Metric::Handshake) => ("threshold", "Handshake threshold exceeded", CEF_SEVERITY),
+        (AlertKind::Block, Metric::Control) => ("control_threshold", "TCP control packet threshold exceeded", CEF_SEVERITY),
+        (AlertKind::Release, Metric::Handshake) => ("release", "Handshake rate back under threshold", CEF_RELEASE_SEVERITY),
+        (AlertKind::Release, Metric::Control) => ("control_release", "TCP control packet rate back under threshold", CEF_RELEASE_SEVERITY),
+        (AlertKind::Novel, _) => ("novel", "Novel ja3 fingerprint", CEF_NOVEL_SEVERITY),
+    };
+    format!("CEF:0|susspekt|susspekt|{}|{}|{}|{}|cs1Label=key cs1={} cs2Label=ja3 cs2={} cs3Label=keyspace cs3={} {} cnt={} cn1Label=threshold cn1={} cn2Label=blockSeconds cn2={} rt={}",
+        escape_header(env!("CARGO_PKG_VERSION")),
+        signature,
+        name,
+        severity,
+        escape_extension(&alert.key),
+        escape_extension(alert.ja3.as_deref().unwrap_or("None")),
+        escape_extension(&alert.keyspace),
+        source,
+        alert.count,
+        alert.threshold,
+        alert.block_seconds,
+        DateTime::<Utc>::from(alert.ts).timestamp_millis())
+}
+
+// CEF header fields escape backslashes and pipes
+fn escape_header(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+// CEF extension values escape backslashes, equals signs and newlines
+fn escape_extension(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+// the syslog HOSTNAME, the nil value "-" when it can't be found
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty() && !name.contains(' '))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn alert() -> Alert {
+        Alert {
+            source: "192.168.0.7".parse().unwrap(),
+            ..Alert::test("579ccef312d18482fc42e2b822ca2430-192.168.0.7", UNIX_EPOCH + Duration::from_millis(1701076465288))
+        }
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape_extension("a=b\\c\nd"), "a\\=b\\\\c\\nd");
+        assert_eq!(escape_header("a|b"), "a\\|b");
+    }
+
+    #[tokio::test]
+    async fn test_udp() {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let retry = RetryPolicy { retries: 0, backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+        let mut sink = SyslogSink::new("siem".to_string(), listener.local_addr().unwrap().to_string(), SyslogProtocol::Udp, DEFAULT_FACILITY, false, retry);
+
+        sink.send(&alert()).await.unwrap();
+
+        let mut buf = [0u8; 2048];
+        let len = tokio::time::timeout(Duration::from_secs(5), listener.recv(&mut buf)).await.unwrap().unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+
+        assert!(message.starts_with("<132>1 2023-11-27T09:14:25.288Z "), "{}", message);
+        assert!(message.contains(" susspekt "), "{}", message);
+        assert!(message.contains(" alert - CEF:0|susspekt|susspekt|"), "{}", message);
+        assert!(message.ends_with("|threshold|Handshake threshold exceeded|7|cs1Label=key cs1=579ccef312d18482fc42e2b822ca2430-192.168.0.7 \
+            cs2Label=ja3 cs2=579ccef312d18482fc42e2b822ca2430 cs3Label=keyspace cs3=default src=192.168.0.7 cnt=1001 \
+            cn1Label=threshold cn1=1000 cn2Label=blockSeconds cn2=86400 rt=1701076465288"), "{}", message);
+    }
+
+    #[test]
+    fn test_release() {
+        let retry = RetryPolicy { retries: 0, backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+        let sink = SyslogSink::new("siem".to_string(), "127.0.0.1:514".to_string(), SyslogProtocol::Udp, DEFAULT_FACILITY, false, retry);
+        let message = sink.format(&Alert { kind: AlertKind::Release, ..alert() });
+        assert!(message.starts_with("<133>1 "), "{}", message);
+        assert!(message.contains("|release|Handshake rate back under threshold|3|"), "{}", message);
+
+        let message = sink.format(&Alert { kind: AlertKind::Novel, ..alert() });
+        assert!(message.starts_with("<134>1 "), "{}", message);
+        assert!(message.contains("|novel|Novel ja3 fingerprint|4|"), "{}", message);
+
+        let message = sink.format(&Alert { metric: Metric::Control, ..alert() });
+        assert!(message.contains("|control_threshold|TCP control packet threshold exceeded|7|"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_framing() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let retry = RetryPolicy { retries: 0, backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+        let mut sink = SyslogSink::new("siem".to_string(), listener.local_addr().unwrap().to_string(), SyslogProtocol::Tcp, DEFAULT_FACILITY, false, retry);
+
+        sink.send(&alert()).await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let expected = sink.format(&alert());
+        let mut buf = vec![0u8; expected.len() + format!("{} ", expected.len()).len()];
+        tokio::io::AsyncReadExt::read_exact(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), format!("{} {}", expected.len(), expected));
+    }
+}
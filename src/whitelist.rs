@@ -3,8 +3,10 @@
 // This file may not be copied, modified, or distributed except according to those terms.
 
 use std::sync::Arc;
-use std::net::Ipv4Addr;
-use ipnetwork::Ipv4Network;
+use std::net::IpAddr;
+use ipnetwork::IpNetwork;
+
+use crate::address;
 
 /**
  * This is the whitelist implementations
@@ -12,26 +14,33 @@ use ipnetwork::Ipv4Network;
 
 #[derive(Clone)]
 pub struct Whitelist {
-    pub networks: Arc<Vec<Ipv4Network>>,
+    pub networks: Arc<Vec<IpNetwork>>,
     pub ja3s: Arc<Vec<String>>,
 }
 
 impl Whitelist {
     // Constructor
-    pub fn new(networks: Arc<Vec<Ipv4Network>>, ja3s: Arc<Vec<String>>) -> Self {
+    pub fn new(networks: Arc<Vec<IpNetwork>>, ja3s: Arc<Vec<String>>) -> Self {
         Whitelist {
             networks,
             ja3s,
         }
     }
 
-    // Check if an IP is whitelisted
+    // Check if an IP is whitelisted, either family
     pub fn is_ip_whitelisted(&self, ip: &str) -> bool {
-        let ip_addr = match ip.parse::<Ipv4Addr>() {
+        let ip_addr = match ip.parse::<IpAddr>() {
             Ok(addr) => addr,
             Err(_) => return false,
         };
 
+        self.is_addr_whitelisted(&ip_addr)
+    }
+
+    // Check if an already parsed address, e.g. a packet source, is whitelisted. IPv4-mapped IPv6
+    // addresses are matched against the IPv4 networks.
+    pub fn is_addr_whitelisted(&self, ip: &IpAddr) -> bool {
+        let ip_addr = address::normalize(ip);
         self.networks.iter().any(|network| network.contains(ip_addr))
     }
 
@@ -43,3 +52,22 @@ impl Whitelist {
         self.ja3s.contains(&ja3.to_string())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_ip_whitelisted() {
+        let networks: Vec<IpNetwork> = vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()];
+        let whitelist = Whitelist::new(Arc::new(networks), Arc::new(Vec::new()));
+
+        assert!(whitelist.is_ip_whitelisted("10.1.2.3"));
+        assert!(whitelist.is_ip_whitelisted("::ffff:10.1.2.3"));
+        assert!(whitelist.is_ip_whitelisted("2001:db8:1::1"));
+        assert!(!whitelist.is_ip_whitelisted("2001:db9::1"));
+        assert!(!whitelist.is_ip_whitelisted("11.0.0.1"));
+        assert!(!whitelist.is_ip_whitelisted("garbage"));
+    }
+}